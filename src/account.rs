@@ -1,124 +1,1775 @@
-use crate::engine::{Amount, ClientId, TxId};
-use crate::errors::AccountingError;
-use crate::transactions::{Event, Transaction};
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use crate::config::{ChargebackPolicy, EngineConfig};
+use crate::engine::{AccountingResult, Amount, ClientId, TxId};
+use crate::errors::{log_rejection, AccountingError};
+use crate::transactions::{AppliedEvent, Currency, Event, LifecycleEvent, Transaction, TxState};
+use std::collections::btree_map::Entry;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use rust_decimal_macros::dec;
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::sync::RwLock;
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{oneshot, RwLock};
+
+/// What the engine sends to an account
+#[derive(Debug)]
+pub enum AccountMessage {
+    /// An event tagged with its position in the engine's input stream
+    Event(u64, Event),
+    /// Reply with the balance after the events before it
+    Snapshot(oneshot::Sender<AccountingResult>),
+    /// Reply with the whole state after the events before it
+    Export(oneshot::Sender<AccountSnapshot>),
+    /// Debit the funds of an [`Event::Transfer`] and reply whether they were debited
+    Transfer(u64, Event, oneshot::Sender<bool>),
+    /// Reply whether the funds of a transfer can be credited, see [`Account::check_credit`]
+    CheckCredit(Amount, oneshot::Sender<Result<(), AccountingError>>),
+    /// Credit the funds of a transfer that passed [`AccountMessage::CheckCredit`]
+    Credit(TxId, Amount),
+}
+
+/// The state of an [`Account`] that can be restored later
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AccountSnapshot {
+    available: Amount,
+    held: Amount,
+    pending: Amount,
+    is_locked: bool,
+    locked_by_chargeback: bool,
+    external: Amount,
+    in_suspense: BTreeMap<TxId, Amount>,
+    shortfall: BTreeMap<TxId, Amount>,
+    processed: usize,
+    clearing: VecDeque<(usize, TxId, Amount)>,
+    reversed: BTreeSet<TxId>,
+    disputes: BTreeSet<TxId>,
+    fees: Vec<Amount>,
+    currency: Option<Currency>,
+    pending_withdrawals: VecDeque<(u64, Transaction)>,
+    deposited: Amount,
+    withdrawn: Amount,
+}
 
 pub struct Account {
     pub id: ClientId,
     pub available: Amount,
     pub held: Amount,
+    /// Deposited funds that haven't cleared yet
+    pub pending: Amount,
     pub is_locked: bool,
-    pub incoming_tx: UnboundedReceiver<Event>,
-    pub transactions: Arc<RwLock<BTreeMap<TxId, Transaction>>>,
+    /// The account is locked because of a chargeback, which only [`Event::Reinstate`] lifts
+    pub locked_by_chargeback: bool,
+    /// Net of the funds that entered (+) or left (-) the system through this account
+    pub external: Amount,
+    pub incoming_tx: Receiver<AccountMessage>,
+    pub transactions: Arc<RwLock<BTreeMap<(ClientId, TxId), Transaction>>>,
+    pub config: Arc<EngineConfig>,
+    /// Held funds of the suspense account, shared by all accounts
+    pub suspense_held: Arc<Mutex<Amount>>,
+    /// The part of each disputed transaction that overflowed into the suspense account
+    pub in_suspense: BTreeMap<TxId, Amount>,
+    /// The part of each disputed transaction that [`EngineConfig::dispute_clamp`] didn't hold
+    /// because it was no longer available
+    pub shortfall: BTreeMap<TxId, Amount>,
+    /// Number of events this account has handled
+    pub processed: usize,
+    /// Pending deposits in the order they clear, with the `processed` count they clear at
+    pub clearing: VecDeque<(usize, TxId, Amount)>,
+    /// Transactions of this account that were cancelled by a compensating transaction
+    pub reversed: BTreeSet<TxId>,
+    /// Transactions this account holds in dispute, the only ones it resolves or charges back
+    pub disputes: BTreeSet<TxId>,
+    /// Fees debited by [`Event::FeeSweep`]
+    pub fees: Vec<Amount>,
+    /// Fixed by the first deposit with a currency
+    pub currency: Option<Currency>,
+    /// Withdrawals waiting for funds with their positions in the input stream,
+    /// see [`EngineConfig::queue_withdrawals`]
+    pub pending_withdrawals: VecDeque<(u64, Transaction)>,
+    /// Sum of the deposits this account applied
+    pub deposited: Amount,
+    /// Sum of the withdrawals this account applied
+    pub withdrawn: Amount,
+    /// The events this account applied, see [`EngineConfig::applied_log`]
+    pub log: Vec<AppliedEvent>,
+    /// The events this account rejected with their positions in the input stream
+    pub rejections: Vec<(u64, Event, AccountingError)>,
+    /// Position in the input stream of the event being handled
+    pub sequence: u64,
+    /// Set by the engine to stop the account and discard its queued events
+    pub purged: Arc<AtomicBool>,
 }
 impl Account {
+    pub fn new(
+        id: ClientId,
+        incoming_tx: Receiver<AccountMessage>,
+        transactions: Arc<RwLock<BTreeMap<(ClientId, TxId), Transaction>>>,
+        config: Arc<EngineConfig>,
+        suspense_held: Arc<Mutex<Amount>>,
+    ) -> Self {
+        Account {
+            id,
+            available: dec!(0),
+            held: dec!(0),
+            pending: dec!(0),
+            is_locked: false,
+            locked_by_chargeback: false,
+            external: dec!(0),
+            incoming_tx,
+            transactions,
+            config,
+            suspense_held,
+            in_suspense: Default::default(),
+            shortfall: Default::default(),
+            processed: 0,
+            clearing: Default::default(),
+            reversed: Default::default(),
+            disputes: Default::default(),
+            fees: Default::default(),
+            currency: None,
+            pending_withdrawals: Default::default(),
+            deposited: dec!(0),
+            withdrawn: dec!(0),
+            log: Default::default(),
+            rejections: Default::default(),
+            sequence: 0,
+            purged: Default::default(),
+        }
+    }
+    /// An account that continues from `snapshot`
+    pub fn restore(
+        id: ClientId,
+        snapshot: AccountSnapshot,
+        incoming_tx: Receiver<AccountMessage>,
+        transactions: Arc<RwLock<BTreeMap<(ClientId, TxId), Transaction>>>,
+        config: Arc<EngineConfig>,
+        suspense_held: Arc<Mutex<Amount>>,
+    ) -> Self {
+        let mut account = Account::new(id, incoming_tx, transactions, config, suspense_held);
+        account.load(snapshot);
+        account
+    }
+    /// Replaces the state of the account with `snapshot`
+    fn load(&mut self, snapshot: AccountSnapshot) {
+        self.available = snapshot.available;
+        self.held = snapshot.held;
+        self.pending = snapshot.pending;
+        self.is_locked = snapshot.is_locked;
+        self.locked_by_chargeback = snapshot.locked_by_chargeback;
+        self.external = snapshot.external;
+        self.in_suspense = snapshot.in_suspense;
+        self.shortfall = snapshot.shortfall;
+        self.processed = snapshot.processed;
+        self.clearing = snapshot.clearing;
+        self.reversed = snapshot.reversed;
+        self.disputes = snapshot.disputes;
+        self.fees = snapshot.fees;
+        self.currency = snapshot.currency;
+        self.pending_withdrawals = snapshot.pending_withdrawals;
+        self.deposited = snapshot.deposited;
+        self.withdrawn = snapshot.withdrawn;
+    }
+    pub fn snapshot(&self) -> AccountSnapshot {
+        AccountSnapshot {
+            available: self.available,
+            held: self.held,
+            pending: self.pending,
+            is_locked: self.is_locked,
+            locked_by_chargeback: self.locked_by_chargeback,
+            external: self.external,
+            in_suspense: self.in_suspense.clone(),
+            shortfall: self.shortfall.clone(),
+            processed: self.processed,
+            clearing: self.clearing.clone(),
+            reversed: self.reversed.clone(),
+            disputes: self.disputes.clone(),
+            fees: self.fees.clone(),
+            currency: self.currency,
+            pending_withdrawals: self.pending_withdrawals.clone(),
+            deposited: self.deposited,
+            withdrawn: self.withdrawn,
+        }
+    }
+    /// The span the events of this account are handled in
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!("account", client = %self.id)
+    }
+    pub fn result(&self) -> AccountingResult {
+        AccountingResult {
+            available: self.available,
+            held: self.held,
+            pending: self.pending,
+            total: self.total(),
+            locked: self.is_locked,
+            purged: self.purged.load(Ordering::SeqCst),
+            external: self.external,
+            disputed_count: self.disputes.len(),
+            log: self.log.clone(),
+        }
+    }
+    /// The available, held and pending funds. Negative if a chargeback took more than was left.
+    pub fn total(&self) -> Amount {
+        self.available + self.held + self.pending
+    }
+    /// Counts a handled event and makes the deposits available that cleared with it
+    fn advance_clearing(&mut self) {
+        self.processed += 1;
+        while let Some((clears_at, _, amount)) = self.clearing.front() {
+            if *clears_at > self.processed {
+                break;
+            }
+            self.pending -= amount;
+            self.available += amount;
+            self.clearing.pop_front();
+        }
+    }
+    /// Splits a disputed `amount` into the part the account holds itself
+    /// and the part that overflows its held cap into the suspense account.
+    fn split_held(&self, amount: Amount) -> (Amount, Amount) {
+        match &self.config.held_cap {
+            Some(held_cap) if amount > dec!(0) => {
+                let room = (held_cap.cap - self.held).max(dec!(0));
+                let excess = (amount - room).max(dec!(0));
+                (amount - excess, excess)
+            }
+            _ => (amount, dec!(0)),
+        }
+    }
+    fn shortfall(&self, tx_id: TxId) -> Amount {
+        self.shortfall.get(&tx_id).copied().unwrap_or_default()
+    }
+    fn suspended(&self, tx_id: TxId) -> Amount {
+        self.in_suspense.get(&tx_id).copied().unwrap_or_default()
+    }
+    /// `amount + delta` unless it doesn't fit into an [`Amount`]
+    fn add(&self, amount: Amount, delta: Amount) -> Result<Amount, AccountingError> {
+        amount
+            .checked_add(delta)
+            .ok_or(AccountingError::AmountOverflow(self.id))
+    }
+    /// `amount - delta` unless it doesn't fit into an [`Amount`]
+    fn sub(&self, amount: Amount, delta: Amount) -> Result<Amount, AccountingError> {
+        amount
+            .checked_sub(delta)
+            .ok_or(AccountingError::AmountOverflow(self.id))
+    }
+    /// Rejects amounts that aren't positive
+    fn check_amount(amount: Amount) -> Result<(), AccountingError> {
+        match amount {
+            _ if amount.is_zero() => Err(AccountingError::ZeroAmount),
+            _ if amount < dec!(0) => Err(AccountingError::NegativeAmount),
+            _ => Ok(()),
+        }
+    }
+    /// Whether debiting `amount` stays within the [`EngineConfig::overdraft_limit`]
+    fn can_withdraw(&self, amount: Amount) -> bool {
+        self.available - amount >= -self.config.overdraft_limit
+    }
+    /// Rejects a transaction of more than [`EngineConfig::max_amount`]
+    fn check_limit(&self, tx: &Transaction) -> Result<(), AccountingError> {
+        match self.config.max_amount {
+            Some(limit) if tx.amount > limit => Err(AccountingError::AmountExceedsLimit {
+                tx_id: tx.id,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Rejects a deposit that arrives disputed unless [`EngineConfig::locked_deposits`]
+    fn check_locked(&self, tx: &Transaction) -> Result<(), AccountingError> {
+        match tx.state {
+            TxState::Normal => Ok(()),
+            TxState::Disputed if self.config.locked_deposits => Ok(()),
+            _ => Err(AccountingError::LockedDeposit(tx.id)),
+        }
+    }
+    /// Rejects a transaction in another currency than the account's
+    fn check_currency(&self, tx: &Transaction) -> Result<(), AccountingError> {
+        match (self.currency, tx.currency) {
+            (Some(expected), Some(found)) if expected != found => {
+                Err(AccountingError::CurrencyMismatch {
+                    client: self.id,
+                    expected,
+                    found,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+    /// With [`EngineConfig::reject_negative_held`], rejects changing held by `delta`
+    /// if that would make it negative.
+    fn check_held(&self, delta: Amount) -> Result<(), AccountingError> {
+        if self.config.reject_negative_held && self.held + delta < dec!(0) {
+            return Err(AccountingError::WouldGoNegative(self.id));
+        }
+        Ok(())
+    }
+    fn release_suspense(
+        in_suspense: &mut BTreeMap<TxId, Amount>,
+        suspense_held: &Mutex<Amount>,
+        tx_id: TxId,
+    ) -> Amount {
+        let excess = in_suspense.remove(&tx_id).unwrap_or_default();
+        *suspense_held.lock().unwrap() -= excess;
+        excess
+    }
     pub async fn process_txs(mut self) -> Self {
-        loop {
-            if let Some(tx) = self.incoming_tx.recv().await {
-                if let Err(_e) = self.handle_tx(tx).await {
-                    // eprintln!("{:?}", e);
-                }
-            } else {
+        while let Some(message) = self.incoming_tx.recv().await {
+            if self.purged.load(Ordering::SeqCst) {
                 break;
             }
+            let (sequence, tx) = match message {
+                AccountMessage::Event(sequence, tx) => (sequence, tx),
+                AccountMessage::Snapshot(reply) => {
+                    let _ = reply.send(self.result());
+                    continue;
+                }
+                AccountMessage::Export(reply) => {
+                    let _ = reply.send(self.snapshot());
+                    continue;
+                }
+                AccountMessage::Transfer(sequence, tx, reply) => {
+                    let _ = reply.send(self.apply(sequence, tx).await);
+                    continue;
+                }
+                AccountMessage::CheckCredit(amount, reply) => {
+                    let _ = reply.send(self.check_credit(amount));
+                    continue;
+                }
+                AccountMessage::Credit(tx_id, amount) => {
+                    self.credit(tx_id, amount);
+                    continue;
+                }
+            };
+            self.apply(sequence, tx).await;
         }
+        self.close();
         self
     }
-    async fn try_insert_tx(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), AccountingError> {
+    /// Handles the event at `sequence` in the input stream and reports the outcome.
+    /// Returns whether the event was applied. An event whose handling panics is rejected
+    /// and leaves the account as it was before the event.
+    pub async fn apply(&mut self, sequence: u64, tx: Event) -> bool {
+        if let Event::Query { reply, .. } = &tx {
+            reply.send(Some(self.result()));
+            return true;
+        }
+        self.sequence = sequence;
+        let event = tx.clone();
+        let checkpoint = self.checkpoint(&tx).await;
+        let handled = CatchUnwind(Box::pin(self.handle_tx(tx))).await;
+        let result = match handled {
+            Ok(result) => result,
+            Err(panic) => {
+                self.roll_back(checkpoint).await;
+                let message = panic_message(panic);
+                let kind = event.kind();
+                tracing::error!(client = %self.id, panic = %message, "panicked on {}", kind);
+                Err(AccountingError::Panicked {
+                    client: self.id,
+                    message,
+                })
+            }
+        };
+        self.advance_clearing();
+        match result {
+            Ok(()) => {
+                if let Some(echo) = &self.config.echo {
+                    let _ = echo.send((sequence, event));
+                }
+                true
+            }
+            Err(e) => {
+                self.reject(sequence, event, e);
+                false
+            }
+        }
+    }
+    /// Saves what handling `tx` can change. The queues are copied whole,
+    /// which costs nothing while they are empty.
+    async fn checkpoint(&self, tx: &Event) -> Checkpoint {
+        let mut tx_ids: Vec<TxId> = tx.tx_id().into_iter().collect();
+        if let Event::Reverse { new_tx_id, .. } = tx {
+            tx_ids.push(*new_tx_id);
+        }
+        // A deposit applies the queued withdrawals it makes affordable
+        if let Event::Deposit(_) = tx {
+            tx_ids.extend(self.pending_withdrawals.iter().map(|(_, tx)| tx.id));
+        }
+        let transactions = self.transactions.read().await;
+        let entries = tx_ids
+            .into_iter()
+            .map(|tx_id| TxEntry {
+                tx_id,
+                stored: transactions.get(&(self.id, tx_id)).cloned(),
+                in_suspense: self.in_suspense.get(&tx_id).copied(),
+                shortfall: self.shortfall.get(&tx_id).copied(),
+                reversed: self.reversed.contains(&tx_id),
+                disputed: self.disputes.contains(&tx_id),
+            })
+            .collect();
+        Checkpoint {
+            available: self.available,
+            held: self.held,
+            pending: self.pending,
+            external: self.external,
+            deposited: self.deposited,
+            withdrawn: self.withdrawn,
+            is_locked: self.is_locked,
+            locked_by_chargeback: self.locked_by_chargeback,
+            currency: self.currency,
+            logged: self.log.len(),
+            rejected: self.rejections.len(),
+            fees: self.fees.len(),
+            clearing: self.clearing.clone(),
+            pending_withdrawals: self.pending_withdrawals.clone(),
+            entries,
+        }
+    }
+    /// Undoes the changes of the event handled since `checkpoint`
+    async fn roll_back(&mut self, checkpoint: Checkpoint) {
+        self.available = checkpoint.available;
+        self.held = checkpoint.held;
+        self.pending = checkpoint.pending;
+        self.external = checkpoint.external;
+        self.deposited = checkpoint.deposited;
+        self.withdrawn = checkpoint.withdrawn;
+        self.is_locked = checkpoint.is_locked;
+        self.locked_by_chargeback = checkpoint.locked_by_chargeback;
+        self.currency = checkpoint.currency;
+        self.log.truncate(checkpoint.logged);
+        self.rejections.truncate(checkpoint.rejected);
+        self.fees.truncate(checkpoint.fees);
+        self.clearing = checkpoint.clearing;
+        self.pending_withdrawals = checkpoint.pending_withdrawals;
         let mut transactions = self.transactions.write().await;
-        if transactions.contains_key(&tx_id) {
-            return Err(AccountingError::TransactionAlreadyExists(tx_id));
-        } else {
-            transactions.insert(tx_id, tx);
+        for entry in checkpoint.entries {
+            let tx_id = entry.tx_id;
+            restore_entry(&mut transactions, (self.id, tx_id), entry.stored);
+            // The suspense account is shared, so only the part of this transaction is put back
+            let suspended = entry.in_suspense.unwrap_or_default() - self.suspended(tx_id);
+            *self.suspense_held.lock().unwrap() += suspended;
+            restore_entry(&mut self.in_suspense, tx_id, entry.in_suspense);
+            restore_entry(&mut self.shortfall, tx_id, entry.shortfall);
+            match entry.reversed {
+                true => self.reversed.insert(tx_id),
+                false => self.reversed.remove(&tx_id),
+            };
+            match entry.disputed {
+                true => self.disputes.insert(tx_id),
+                false => self.disputes.remove(&tx_id),
+            };
         }
-        Ok(())
     }
-    async fn handle_tx(&mut self, tx: Event) -> Result<(), AccountingError> {
+    /// Checks that the account can receive `amount` from an [`Event::Transfer`]
+    pub fn check_credit(&self, amount: Amount) -> Result<(), AccountingError> {
         if self.is_locked {
             return Err(AccountingError::AccountFrozen(self.id));
         }
+        self.add(self.available, amount)?;
+        self.add(self.external, amount)?;
+        self.add(self.total(), amount)?;
+        Ok(())
+    }
+    /// Credits the funds of the transfer `tx_id` after [`Account::check_credit`] passed
+    pub fn credit(&mut self, tx_id: TxId, amount: Amount) {
+        if !self.config.dry_run {
+            self.available += amount;
+            self.external += amount;
+        }
+        self.record("transfer", Some(tx_id));
+    }
+    /// Rejects the withdrawals that are still queued once the events of the account ended
+    pub fn close(&mut self) {
+        for (sequence, tx) in self.drain_pending_withdrawals() {
+            let error = AccountingError::InsufficientFunds(self.id);
+            self.config.count(|m| &m.insufficient_funds);
+            self.reject(sequence, Event::Withdrawal(tx), error);
+        }
+        self.emit(LifecycleEvent::AccountClosed(self.id));
+        tracing::debug!(
+            client = %self.id,
+            processed = self.processed,
+            rejected = self.rejections.len(),
+            "closed account"
+        );
+    }
+    /// Removes the queued withdrawals that never became affordable
+    pub fn drain_pending_withdrawals(&mut self) -> Vec<(u64, Transaction)> {
+        self.pending_withdrawals.drain(..).collect()
+    }
+    /// Applies the queued withdrawals in order until one is still unaffordable
+    async fn retry_withdrawals(&mut self) {
+        while let Some((_, tx)) = self.pending_withdrawals.front() {
+            if !self.can_withdraw(tx.amount) {
+                break;
+            }
+            let (sequence, tx) = self.pending_withdrawals.pop_front().unwrap();
+            let tx_id = tx.id;
+            match self.withdraw(tx.clone()).await {
+                Ok(()) => self.record("withdrawal", Some(tx_id)),
+                Err(e) => self.reject(sequence, Event::Withdrawal(tx), e),
+            }
+        }
+    }
+    async fn withdraw(&mut self, mut tx: Transaction) -> Result<(), AccountingError> {
+        tx.is_withdrawal = true;
+        let available = self.sub(self.available, tx.amount)?;
+        let external = self.sub(self.external, tx.amount)?;
+        self.try_insert_tx(tx.id, tx.clone()).await?;
+        self.available = available;
+        self.external = external;
+        self.withdrawn += tx.amount;
+        Ok(())
+    }
+    fn reject(&mut self, sequence: u64, event: Event, error: AccountingError) {
+        self.config.count_rejected();
+        log_rejection(&event, &error);
+        self.rejections.push((sequence, event, error));
+    }
+    fn record(&mut self, kind: &'static str, tx_id: Option<TxId>) {
+        if self.config.applied_log {
+            self.log.push(AppliedEvent {
+                tx_id,
+                kind,
+                available: self.available,
+                held: self.held,
+            });
+        }
+    }
+    fn unlock(&mut self) {
+        if self.is_locked {
+            self.is_locked = false;
+            self.emit(LifecycleEvent::AccountReinstated(self.id));
+        }
+    }
+    pub fn emit(&self, event: LifecycleEvent) {
+        if let Some(lifecycle) = &self.config.lifecycle {
+            // A consumer that hung up doesn't stop the accounting
+            let _ = lifecycle.send(event);
+        }
+    }
+    async fn try_insert_tx(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), AccountingError> {
+        match self.transactions.write().await.entry((self.id, tx_id)) {
+            Entry::Occupied(_) => Err(AccountingError::TransactionAlreadyExists(tx_id)),
+            Entry::Vacant(entry) => {
+                entry.insert(tx);
+                Ok(())
+            }
+        }
+    }
+    /// With [`EngineConfig::idempotent`], whether `tx` repeats the recorded transaction of its id
+    async fn is_replay(&self, tx: &Transaction, is_withdrawal: bool) -> bool {
+        if !self.config.idempotent {
+            return false;
+        }
+        match self.transactions.read().await.get(&(self.id, tx.id)) {
+            Some(recorded) => {
+                recorded.amount == tx.amount
+                    && recorded.currency == tx.currency
+                    && recorded.is_withdrawal == is_withdrawal
+            }
+            None => false,
+        }
+    }
+    /// Returns the amount of `tx` that is already disputed and the amount a dispute would add
+    fn dispute_amount(
+        tx: &Transaction,
+        partial: Option<Amount>,
+    ) -> Result<(Amount, Amount), AccountingError> {
+        let already_disputed = match tx.state {
+            TxState::Normal => dec!(0),
+            // A partial dispute can add to an earlier one
+            TxState::Disputed if partial.is_some() && tx.disputed.is_some() => {
+                tx.disputed_amount()
+            }
+            TxState::Disputed => {
+                return Err(AccountingError::TransactionIsAlreadyLocked {
+                    tx_id: tx.id,
+                    line: tx.line,
+                })
+            }
+            TxState::ChargedBack => {
+                return Err(AccountingError::TransactionAlreadyChargedBack(tx.id))
+            }
+        };
+        if let Some(amount) = partial {
+            Self::check_amount(amount)?;
+        }
+        let amount = match partial {
+            Some(amount) if already_disputed + amount > tx.amount => {
+                return Err(AccountingError::InvalidAmount)
+            }
+            Some(amount) => amount,
+            None => tx.amount,
+        };
+        Ok((already_disputed, amount))
+    }
+    /// Holds the funds of `tx_id`, or only the `partial` amount of it
+    async fn dispute(
+        &mut self,
+        tx_id: TxId,
+        partial: Option<Amount>,
+    ) -> Result<(), AccountingError> {
+        if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&(self.id, tx_id)) {
+            let (already_disputed, mut amount) = Self::dispute_amount(to_lock_tx, partial)?;
+            let pending = self.clearing.iter().position(|(_, id, _)| *id == tx_id);
+            let mut shortfall = dec!(0);
+            let withdrawal = to_lock_tx.is_withdrawal;
+            if self.config.dispute_clamp
+                && !withdrawal
+                && pending.is_none()
+                && amount > self.available
+            {
+                shortfall = amount - self.available.max(dec!(0));
+                amount -= shortfall;
+            }
+            let (held, excess) = self.split_held(amount);
+            self.check_held(held)?;
+            let (mut external, mut pending_funds, mut available) =
+                (self.external, self.pending, self.available);
+            match pending {
+                // The withdrawn funds are held until the dispute is settled
+                _ if withdrawal => external = self.add(external, amount)?,
+                // A deposit that hasn't cleared yet is held straight from pending
+                Some(_) => pending_funds = self.sub(pending_funds, amount)?,
+                None => available = self.sub(available, amount)?,
+            }
+            let held = self.add(self.held, held)?;
+            self.add(*self.suspense_held.lock().unwrap(), excess)?;
+            to_lock_tx.state = TxState::Disputed;
+            to_lock_tx.disputed = partial.map(|partial| already_disputed + partial);
+            self.disputes.insert(tx_id);
+            if let Some(index) = pending.filter(|_| !withdrawal) {
+                let (_, _, clearing) = &mut self.clearing[index];
+                *clearing -= amount;
+                if clearing.is_zero() {
+                    self.clearing.remove(index);
+                }
+            }
+            self.external = external;
+            self.pending = pending_funds;
+            self.available = available;
+            self.held = held;
+            if excess > dec!(0) {
+                *self.suspense_held.lock().unwrap() += excess;
+                *self.in_suspense.entry(tx_id).or_default() += excess;
+            }
+            if shortfall > dec!(0) {
+                *self.shortfall.entry(tx_id).or_default() += shortfall;
+            }
+            Ok(())
+        } else {
+            Err(AccountingError::TransactionDoesntExist(tx_id))
+        }
+    }
+    /// Checks `tx` like [`Account::handle_tx`] and keeps track of the transactions it refers to,
+    /// but never touches the balances. Checks that depend on the balances are skipped.
+    async fn validate(&mut self, tx: Event) -> Result<(), AccountingError> {
         match tx {
             Event::Deposit(tx) => {
-                self.try_insert_tx(tx.id, tx.clone()).await?;
-                if tx.amount <= dec!(0) {
-                    return Err(AccountingError::InvalidAmount);
-                }
-                if tx.is_locked {
-                    self.held += tx.amount;
-                } else {
-                    self.available += tx.amount;
+                if self.is_replay(&tx, false).await {
+                    return Ok(());
                 }
+                self.check_currency(&tx)?;
+                self.check_limit(&tx)?;
+                Self::check_amount(tx.amount)?;
+                self.check_locked(&tx)?;
+                let currency = tx.currency;
+                self.try_insert_tx(tx.id, tx).await?;
+                self.currency = self.currency.or(currency);
             }
             Event::Withdrawal(mut tx) => {
-                if self.available < tx.amount {
-                    return Err(AccountingError::InsufficientFunds(self.id));
+                if self.is_replay(&tx, true).await {
+                    return Ok(());
                 }
-                if tx.amount <= dec!(0) {
-                    return Err(AccountingError::InvalidAmount);
+                self.check_currency(&tx)?;
+                self.check_limit(&tx)?;
+                if tx.amount.is_zero() && self.config.allow_zero_withdrawals {
+                    return Ok(());
                 }
-                tx.amount *= dec!(-1); // Invert amount to reflect the withdrawal in the tx catalog
-                self.try_insert_tx(tx.id, tx.clone()).await?;
-                self.available += tx.amount;
+                Self::check_amount(tx.amount)?;
+                tx.is_withdrawal = true;
+                self.try_insert_tx(tx.id, tx).await?;
             }
-            Event::Dispute { tx_id, .. } => {
-                if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&tx_id) {
-                    if to_lock_tx.is_locked {
-                        return Err(AccountingError::TransactionIsAlreadyLocked(tx_id));
-                    }
-                    if to_lock_tx.client != self.id {
-                        return Err(AccountingError::TransactionDoesntBelongToClient {
+            Event::Dispute { tx_id, .. } | Event::PartialDispute { tx_id, .. } => {
+                let partial = match tx {
+                    Event::PartialDispute { amount, .. } => Some(amount),
+                    _ => None,
+                };
+                let key = (self.id, tx_id);
+                if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&key) {
+                    let (already_disputed, _) = Self::dispute_amount(to_lock_tx, partial)?;
+                    to_lock_tx.state = TxState::Disputed;
+                    to_lock_tx.disputed = partial.map(|partial| already_disputed + partial);
+                    self.disputes.insert(tx_id);
+                } else {
+                    return Err(AccountingError::TransactionDoesntExist(tx_id));
+                }
+            }
+            Event::Resolve { tx_id, .. } | Event::Chargeback { tx_id, .. } => {
+                let key = (self.id, tx_id);
+                if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&key) {
+                    if to_lock_tx.state != TxState::Disputed || !self.disputes.contains(&tx_id) {
+                        return Err(AccountingError::TransactionIsNotDisputed {
                             tx_id,
-                            client: self.id,
+                            line: to_lock_tx.line,
                         });
                     }
-                    to_lock_tx.is_locked = true;
-                    self.available -= to_lock_tx.amount;
-                    self.held += to_lock_tx.amount;
+                    to_lock_tx.disputed = None;
+                    self.disputes.remove(&tx_id);
+                    if let Event::Chargeback { .. } = tx {
+                        to_lock_tx.state = TxState::ChargedBack;
+                        self.is_locked = true;
+                        self.locked_by_chargeback = true;
+                    } else {
+                        to_lock_tx.state = TxState::Normal;
+                    }
                 } else {
                     return Err(AccountingError::TransactionDoesntExist(tx_id));
                 }
             }
+            Event::Reverse {
+                tx_id, new_tx_id, ..
+            } => {
+                let original = match self.transactions.read().await.get(&(self.id, tx_id)) {
+                    Some(original) => original.clone(),
+                    None => return Err(AccountingError::TransactionDoesntExist(tx_id)),
+                };
+                if original.state == TxState::Disputed {
+                    return Err(AccountingError::TransactionIsAlreadyLocked {
+                        tx_id,
+                        line: original.line,
+                    });
+                }
+                let compensation = Transaction {
+                    is_withdrawal: !original.is_withdrawal,
+                    ..Transaction::new(new_tx_id, self.id, original.amount)
+                };
+                self.try_insert_tx(new_tx_id, compensation).await?;
+                self.reversed.insert(tx_id);
+            }
+            Event::FeeSweep { amount } | Event::Transfer { amount, .. } => {
+                Self::check_amount(amount)?;
+            }
+            Event::Freeze { .. } | Event::Unfreeze { .. } | Event::Reinstate { .. } => {
+                unreachable!("administrative events are handled by Account::handle_tx")
+            }
+            Event::Query { .. } => unreachable!("queries are answered by Account::apply"),
+        }
+        Ok(())
+    }
+    async fn handle_tx(&mut self, tx: Event) -> Result<(), AccountingError> {
+        if let Some(limit) = &self.config.event_limit {
+            if self.processed >= limit.max {
+                if limit.freeze && !self.is_locked {
+                    self.is_locked = true;
+                    self.config.count(|m| &m.frozen_accounts);
+                    self.emit(LifecycleEvent::AccountFrozen(self.id));
+                }
+                return Err(AccountingError::ClientEventLimitExceeded(self.id));
+            }
+        }
+        if let Event::Dispute { tx_id, .. }
+        | Event::PartialDispute { tx_id, .. }
+        | Event::Resolve { tx_id, .. }
+        | Event::Chargeback { tx_id, .. }
+        | Event::Reverse { tx_id, .. } = &tx
+        {
+            let transactions = self.transactions.read().await;
+            if let Some(tx) = transactions.get(&(self.id, *tx_id)) {
+                if tx.state == TxState::ChargedBack {
+                    return Err(AccountingError::TransactionAlreadyChargedBack(*tx_id));
+                }
+            }
+        }
+        if let Event::Dispute { tx_id, .. }
+        | Event::PartialDispute { tx_id, .. }
+        | Event::Reverse { tx_id, .. } = &tx
+        {
+            if self.reversed.contains(tx_id) {
+                return Err(AccountingError::TransactionAlreadyReversed(*tx_id));
+            }
+        }
+        let admin = matches!(
+            tx,
+            Event::Freeze { .. } | Event::Unfreeze { .. } | Event::Reinstate { .. }
+        );
+        // Disputes of earlier transactions still have to be settled on a frozen account
+        let settlement = matches!(
+            tx,
+            Event::Dispute { .. }
+                | Event::PartialDispute { .. }
+                | Event::Resolve { .. }
+                | Event::Chargeback { .. }
+        );
+        if self.is_locked && !admin && !settlement {
+            return Err(AccountingError::AccountFrozen(self.id));
+        }
+        if self.config.dry_run && !admin {
+            return self.validate(tx).await;
+        }
+        let (kind, tx_id) = (tx.kind(), tx.tx_id());
+        let mut queued = false;
+        match tx {
+            Event::Deposit(tx) => {
+                if self.is_replay(&tx, false).await {
+                    return Ok(());
+                }
+                self.check_currency(&tx)?;
+                self.check_limit(&tx)?;
+                Self::check_amount(tx.amount)?;
+                self.check_locked(&tx)?;
+                let disputed = tx.state == TxState::Disputed;
+                let clearing = !disputed && self.config.clearing_delay > 0;
+                let balance = match () {
+                    _ if disputed => self.held,
+                    _ if clearing => self.pending,
+                    _ => self.available,
+                };
+                let balance = self.add(balance, tx.amount)?;
+                let external = self.add(self.external, tx.amount)?;
+                // The total has to fit too, since pending funds become available later
+                self.add(self.total(), tx.amount)?;
+                self.try_insert_tx(tx.id, tx.clone()).await?;
+                if disputed {
+                    self.held = balance;
+                    self.disputes.insert(tx.id);
+                } else if clearing {
+                    let clears_at = self.processed + 1 + self.config.clearing_delay;
+                    self.clearing.push_back((clears_at, tx.id, tx.amount));
+                    self.pending = balance;
+                } else {
+                    self.available = balance;
+                }
+                self.external = external;
+                self.deposited += tx.amount;
+                self.currency = self.currency.or(tx.currency);
+                self.config.count(|m| &m.deposits);
+            }
+            Event::Withdrawal(tx) => {
+                if self.is_replay(&tx, true).await {
+                    return Ok(());
+                }
+                self.check_currency(&tx)?;
+                self.check_limit(&tx)?;
+                if tx.amount.is_zero() && self.config.allow_zero_withdrawals {
+                    return Ok(());
+                }
+                Self::check_amount(tx.amount)?;
+                if self.config.queue_withdrawals
+                    && (!self.can_withdraw(tx.amount) || !self.pending_withdrawals.is_empty())
+                {
+                    self.pending_withdrawals.push_back((self.sequence, tx));
+                    queued = true;
+                } else if !self.can_withdraw(tx.amount) {
+                    self.config.count(|m| &m.insufficient_funds);
+                    return Err(AccountingError::InsufficientFunds(self.id));
+                } else {
+                    self.withdraw(tx).await?;
+                }
+            }
+            Event::Dispute { tx_id, .. } => {
+                self.dispute(tx_id, None).await?;
+                self.config.count(|m| &m.disputes);
+            }
+            Event::PartialDispute { tx_id, amount, .. } => {
+                self.dispute(tx_id, Some(amount)).await?;
+                self.config.count(|m| &m.disputes);
+            }
             Event::Resolve { tx_id, .. } => {
-                if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&tx_id) {
-                    if !to_lock_tx.is_locked {
-                        return Err(AccountingError::TransactionIsNotDisputed(tx_id));
-                    }
-                    if to_lock_tx.client != self.id {
-                        return Err(AccountingError::TransactionDoesntBelongToClient {
+                let key = (self.id, tx_id);
+                if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&key) {
+                    if to_lock_tx.state != TxState::Disputed || !self.disputes.contains(&tx_id) {
+                        return Err(AccountingError::TransactionIsNotDisputed {
                             tx_id,
-                            client: self.id,
+                            line: to_lock_tx.line,
                         });
                     }
-                    to_lock_tx.is_locked = false;
-                    self.available += to_lock_tx.amount;
-                    self.held -= to_lock_tx.amount;
+                    let amount = to_lock_tx.disputed_amount() - self.shortfall(tx_id);
+                    self.check_held(-(amount - self.suspended(tx_id)))?;
+                    let held = self.sub(self.held, amount - self.suspended(tx_id))?;
+                    // A resolved withdrawal stands, so its funds leave again
+                    let (external, available) = if to_lock_tx.is_withdrawal {
+                        (self.sub(self.external, amount)?, self.available)
+                    } else {
+                        (self.external, self.add(self.available, amount)?)
+                    };
+                    to_lock_tx.state = TxState::Normal;
+                    to_lock_tx.disputed = None;
+                    self.disputes.remove(&tx_id);
+                    self.shortfall.remove(&tx_id);
+                    Self::release_suspense(&mut self.in_suspense, &self.suspense_held, tx_id);
+                    self.external = external;
+                    self.available = available;
+                    self.held = held;
                 } else {
                     return Err(AccountingError::TransactionDoesntExist(tx_id));
                 }
             }
             Event::Chargeback { tx_id, .. } => {
-                if let Some(to_lock_tx) = self.transactions.read().await.get(&tx_id) {
-                    if !to_lock_tx.is_locked {
-                        return Err(AccountingError::TransactionIsNotDisputed(tx_id));
-                    }
-                    if to_lock_tx.client != self.id {
-                        return Err(AccountingError::TransactionDoesntBelongToClient {
+                let key = (self.id, tx_id);
+                // The write lock is held until the chargeback is applied,
+                // so the transaction can't change between the check and the update
+                if let Some(to_lock_tx) = self.transactions.write().await.get_mut(&key) {
+                    if to_lock_tx.state != TxState::Disputed || !self.disputes.contains(&tx_id) {
+                        return Err(AccountingError::TransactionIsNotDisputed {
                             tx_id,
-                            client: self.id,
+                            line: to_lock_tx.line,
                         });
                     }
-                    self.held -= to_lock_tx.amount;
+                    let amount = to_lock_tx.disputed_amount() - self.shortfall(tx_id);
+                    self.check_held(-(amount - self.suspended(tx_id)))?;
+                    let held = self.sub(self.held, amount - self.suspended(tx_id))?;
+                    // A charged back withdrawal is credited back to the client
+                    let (mut external, mut available) = if to_lock_tx.is_withdrawal {
+                        (self.external, self.add(self.available, amount)?)
+                    } else {
+                        (self.sub(self.external, amount)?, self.available)
+                    };
+                    if available < dec!(0) {
+                        match self.config.chargeback_policy {
+                            ChargebackPolicy::AllowNegative => {}
+                            ChargebackPolicy::ClampToZero => {
+                                external = self.sub(external, available)?;
+                                available = dec!(0);
+                            }
+                            ChargebackPolicy::Reject => {
+                                return Err(AccountingError::InsufficientFunds(self.id))
+                            }
+                        }
+                    }
+                    to_lock_tx.state = TxState::ChargedBack;
+                    self.disputes.remove(&tx_id);
+                    self.shortfall.remove(&tx_id);
+                    Self::release_suspense(&mut self.in_suspense, &self.suspense_held, tx_id);
+                    self.external = external;
+                    self.available = available;
+                    self.held = held;
+                    self.config.count(|m| &m.chargebacks);
+                    if !self.is_locked {
+                        self.config.count(|m| &m.frozen_accounts);
+                        self.emit(LifecycleEvent::AccountFrozen(self.id));
+                        if let Some(callback) = &self.config.on_chargeback_freeze {
+                            (callback.0)(self.id);
+                        }
+                    }
                     self.is_locked = true;
+                    self.locked_by_chargeback = true;
                 } else {
                     return Err(AccountingError::TransactionDoesntExist(tx_id));
                 }
-                self.transactions.write().await.remove(&tx_id);
             }
+            Event::Reverse {
+                tx_id, new_tx_id, ..
+            } => {
+                let original = match self.transactions.read().await.get(&(self.id, tx_id)) {
+                    Some(original) => original.clone(),
+                    None => return Err(AccountingError::TransactionDoesntExist(tx_id)),
+                };
+                if original.state == TxState::Disputed {
+                    return Err(AccountingError::TransactionIsAlreadyLocked {
+                        tx_id,
+                        line: original.line,
+                    });
+                }
+                let compensation = Transaction {
+                    is_withdrawal: !original.is_withdrawal,
+                    ..Transaction::new(new_tx_id, self.id, original.amount)
+                };
+                let amount = compensation.signed_amount();
+                let pending = self.clearing.iter().position(|(_, id, _)| *id == tx_id);
+                let balance = match pending {
+                    Some(_) => self.add(self.pending, amount)?,
+                    None => self.add(self.available, amount)?,
+                };
+                if pending.is_none() && !self.can_withdraw(-amount) {
+                    return Err(AccountingError::InsufficientFunds(self.id));
+                }
+                let external = self.add(self.external, amount)?;
+                self.try_insert_tx(new_tx_id, compensation.clone()).await?;
+                match pending {
+                    Some(index) => {
+                        self.clearing.remove(index);
+                        self.pending = balance;
+                    }
+                    None => self.available = balance,
+                }
+                self.external = external;
+                self.reversed.insert(tx_id);
+            }
+            // The engine credits the funds to the receiving account once they were debited here
+            Event::Transfer { amount, .. } => {
+                Self::check_amount(amount)?;
+                if !self.can_withdraw(amount) {
+                    self.config.count(|m| &m.insufficient_funds);
+                    return Err(AccountingError::InsufficientFunds(self.id));
+                }
+                let available = self.sub(self.available, amount)?;
+                self.external = self.sub(self.external, amount)?;
+                self.available = available;
+            }
+            Event::Freeze { .. } => {
+                if !self.is_locked {
+                    self.is_locked = true;
+                    self.config.count(|m| &m.frozen_accounts);
+                    self.emit(LifecycleEvent::AccountFrozen(self.id));
+                }
+            }
+            Event::Unfreeze { .. } => {
+                if self.locked_by_chargeback {
+                    return Err(AccountingError::CannotUnfreezeChargedBack(self.id));
+                }
+                self.unlock();
+            }
+            Event::Reinstate { .. } => {
+                self.locked_by_chargeback = false;
+                self.unlock();
+            }
+            Event::FeeSweep { amount } => {
+                Self::check_amount(amount)?;
+                if !self.can_withdraw(amount) {
+                    return Err(AccountingError::InsufficientFunds(self.id));
+                }
+                let external = self.sub(self.external, amount)?;
+                self.available = self.sub(self.available, amount)?;
+                self.external = external;
+                self.fees.push(amount);
+            }
+            Event::Query { .. } => unreachable!("queries are answered by Account::apply"),
+        }
+        if let Some(rounding) = &self.config.rounding {
+            let total = self.total();
+            self.available = rounding.round(self.available);
+            self.held = rounding.round(self.held);
+            self.pending = rounding.round(self.pending);
+            // The fractions that rounding creates or destroys enter or leave the system
+            self.external += self.total() - total;
         }
+        // A queued withdrawal is logged once it is applied
+        if !queued {
+            self.record(kind, tx_id);
+        }
+        if kind == "deposit" {
+            self.retry_withdrawals().await;
+        }
+        debug_assert!(
+            !self.config.reject_negative_held || self.held >= dec!(0),
+            "held of client {} went negative",
+            self.id
+        );
         Ok(())
     }
 }
+
+/// What [`Account::apply`] puts back when handling an event panics: the balances,
+/// the lengths of the records that only grow and the entries of the transactions
+/// the event refers to
+struct Checkpoint {
+    available: Amount,
+    held: Amount,
+    pending: Amount,
+    external: Amount,
+    deposited: Amount,
+    withdrawn: Amount,
+    is_locked: bool,
+    locked_by_chargeback: bool,
+    currency: Option<Currency>,
+    logged: usize,
+    rejected: usize,
+    fees: usize,
+    clearing: VecDeque<(usize, TxId, Amount)>,
+    pending_withdrawals: VecDeque<(u64, Transaction)>,
+    entries: Vec<TxEntry>,
+}
+/// The state of one transaction the event of a [`Checkpoint`] can touch
+struct TxEntry {
+    tx_id: TxId,
+    stored: Option<Transaction>,
+    in_suspense: Option<Amount>,
+    shortfall: Option<Amount>,
+    reversed: bool,
+    disputed: bool,
+}
+/// Sets `key` to `value`, or removes it if there was no value
+fn restore_entry<K: Ord, V>(map: &mut BTreeMap<K, V>, key: K, value: Option<V>) {
+    match value {
+        Some(value) => map.insert(key, value),
+        None => map.remove(&key),
+    };
+}
+/// Resolves to the output of a future or to the payload of a panic while polling it
+struct CatchUnwind<F>(F);
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = std::thread::Result<F::Output>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = &mut self.0;
+        match std::panic::catch_unwind(AssertUnwindSafe(|| Pin::new(future).poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+/// The message a panic was raised with, if it has one
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().copied().unwrap_or_default().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::account::Account;
+    use crate::config::EngineConfig;
+    use crate::engine::{ClientId, TxId};
+    use crate::errors::AccountingError;
+    use crate::transactions::{Event, Transaction, TxState};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+
+    fn account(config: EngineConfig) -> Account {
+        let (_, receiver) = channel(1);
+        Account::new(
+            ClientId(0),
+            receiver,
+            Default::default(),
+            Arc::new(config),
+            Default::default(),
+        )
+    }
+    fn withdrawal(id: u32, amount: rust_decimal::Decimal) -> Event {
+        Event::withdrawal(TxId(id), ClientId(0), amount)
+    }
+
+    #[tokio::test]
+    async fn zero_withdrawal_on_funded_account() {
+        let mut account = account(EngineConfig::default());
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        let result = account.handle_tx(withdrawal(1, dec!(0))).await;
+        assert!(matches!(result, Err(AccountingError::ZeroAmount)));
+        assert_eq!(dec!(5), account.available);
+    }
+    #[tokio::test]
+    async fn zero_withdrawal_on_empty_account() {
+        let mut account = account(EngineConfig::default());
+        let result = account.handle_tx(withdrawal(0, dec!(0))).await;
+        assert!(matches!(result, Err(AccountingError::ZeroAmount)));
+        // A negative balance must not mask the invalid amount as insufficient funds
+        account.available = dec!(-1);
+        let result = account.handle_tx(withdrawal(1, dec!(0))).await;
+        assert!(matches!(result, Err(AccountingError::ZeroAmount)));
+        assert_eq!(dec!(-1), account.available);
+    }
+    #[tokio::test]
+    async fn zero_withdrawal_as_no_op() {
+        let mut account = account(EngineConfig {
+            allow_zero_withdrawals: true,
+            ..Default::default()
+        });
+        assert!(account.handle_tx(withdrawal(0, dec!(0))).await.is_ok());
+        assert!(matches!(
+            account.handle_tx(withdrawal(1, dec!(-1))).await,
+            Err(AccountingError::NegativeAmount)
+        ));
+        assert_eq!(dec!(0), account.available);
+        assert!(account.transactions.read().await.is_empty());
+    }
+    #[tokio::test]
+    async fn zero_and_negative_deposits() {
+        let mut account = account(EngineConfig::default());
+        let result = account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(0))).await;
+        assert!(matches!(result, Err(AccountingError::ZeroAmount)));
+        let result = account.handle_tx(Event::deposit(TxId(1), ClientId(0), dec!(-2))).await;
+        assert!(matches!(result, Err(AccountingError::NegativeAmount)));
+        assert!(account.transactions.read().await.is_empty());
+        // The rejected deposits didn't use up their tx ids
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(1)))
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::deposit(TxId(1), ClientId(0), dec!(2)))
+            .await
+            .unwrap();
+        assert_eq!(dec!(3), account.available);
+    }
+    #[tokio::test]
+    async fn dispute_charged_back_transaction() {
+        let mut account = account(EngineConfig::default());
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) })
+            .await
+            .unwrap();
+        let result = account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::TransactionAlreadyChargedBack(TxId(0)))));
+        let result = account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(1) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::TransactionDoesntExist(TxId(1)))));
+        let result = account.handle_tx(Event::deposit(TxId(1), ClientId(0), dec!(1))).await;
+        assert!(matches!(result, Err(AccountingError::AccountFrozen(ClientId(0)))));
+        assert_eq!((dec!(0), dec!(0)), (account.available, account.held));
+        assert!(account.transactions.read().await.contains_key(&(ClientId(0), TxId(0))));
+    }
+    #[tokio::test]
+    async fn frozen_account_settles_outstanding_disputes() {
+        let mut account = account(EngineConfig::default());
+        for tx_id in [TxId(0), TxId(1)] {
+            account
+                .handle_tx(Event::deposit(tx_id, ClientId(0), dec!(5)))
+                .await
+                .unwrap();
+            account
+                .handle_tx(Event::Dispute { client: ClientId(0), tx_id })
+                .await
+                .unwrap();
+        }
+        account
+            .handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) })
+            .await
+            .unwrap();
+        assert!(account.is_locked);
+        account
+            .handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(1) })
+            .await
+            .unwrap();
+        assert_eq!((dec!(5), dec!(0)), (account.available, account.held));
+        account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(1) })
+            .await
+            .unwrap();
+        assert_eq!((dec!(0), dec!(5)), (account.available, account.held));
+        let result = account.handle_tx(Event::withdrawal(TxId(2), ClientId(0), dec!(1))).await;
+        assert!(matches!(result, Err(AccountingError::AccountFrozen(ClientId(0)))));
+        assert!(account.is_locked);
+    }
+    #[tokio::test]
+    async fn total_of_positive_zero_and_negative_balances() {
+        let mut account = account(EngineConfig::default());
+        assert_eq!(dec!(0), account.total());
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::deposit(TxId(1), ClientId(0), dec!(2.5)))
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(1) })
+            .await
+            .unwrap();
+        assert_eq!((dec!(5), dec!(2.5)), (account.available, account.held));
+        assert_eq!(dec!(7.5), account.total());
+        account
+            .handle_tx(Event::withdrawal(TxId(2), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(1) })
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::withdrawal(TxId(3), ClientId(0), dec!(2.5)))
+            .await
+            .unwrap();
+        assert_eq!(dec!(0), account.total());
+        account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) })
+            .await
+            .unwrap();
+        assert_eq!((dec!(-5), dec!(0)), (account.available, account.held));
+        assert_eq!(dec!(-5), account.total());
+        let result = account.result();
+        assert_eq!(account.total(), result.total);
+        assert!(result.is_consistent());
+    }
+    #[tokio::test]
+    async fn withdrawals_within_the_overdraft_limit() {
+        let mut account = account(EngineConfig {
+            overdraft_limit: dec!(10),
+            ..Default::default()
+        });
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        account.handle_tx(withdrawal(1, dec!(12))).await.unwrap();
+        assert_eq!(dec!(-7), account.available);
+        account.handle_tx(withdrawal(2, dec!(3))).await.unwrap();
+        assert_eq!(dec!(-10), account.available);
+        assert_eq!(dec!(-10), account.external);
+    }
+    #[tokio::test]
+    async fn fee_sweeps_and_transfers_within_the_overdraft_limit() {
+        let mut account = account(EngineConfig {
+            overdraft_limit: dec!(10),
+            ..Default::default()
+        });
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        account.handle_tx(Event::FeeSweep { amount: dec!(8) }).await.unwrap();
+        assert_eq!(dec!(-3), account.available);
+        let transfer = |amount| Event::Transfer {
+            from: ClientId(0),
+            to: ClientId(1),
+            tx_id: TxId(1),
+            amount,
+        };
+        account.handle_tx(transfer(dec!(7))).await.unwrap();
+        assert_eq!((dec!(-10), dec!(-10)), (account.available, account.external));
+        let result = account.handle_tx(transfer(dec!(1))).await;
+        assert!(matches!(result, Err(AccountingError::InsufficientFunds(ClientId(0)))));
+        let result = account.handle_tx(Event::FeeSweep { amount: dec!(1) }).await;
+        assert!(matches!(result, Err(AccountingError::InsufficientFunds(ClientId(0)))));
+    }
+    #[tokio::test]
+    async fn withdrawal_beyond_the_overdraft_limit_is_rejected() {
+        let mut account = account(EngineConfig {
+            overdraft_limit: dec!(10),
+            ..Default::default()
+        });
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        let result = account.handle_tx(withdrawal(1, dec!(15.01))).await;
+        assert!(matches!(result, Err(AccountingError::InsufficientFunds(ClientId(0)))));
+        assert_eq!(dec!(5), account.available);
+        assert!(account.transactions.read().await.get(&(ClientId(0), TxId(1))).is_none());
+    }
+    #[tokio::test]
+    async fn replayed_transactions_are_skipped() {
+        let mut account = account(EngineConfig {
+            idempotent: true,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            account
+                .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+                .await
+                .unwrap();
+            account.handle_tx(withdrawal(1, dec!(2))).await.unwrap();
+        }
+        assert_eq!(dec!(3), account.available);
+        assert_eq!(2, account.transactions.read().await.len());
+    }
+    #[tokio::test]
+    async fn reused_ids_with_other_fields_are_rejected() {
+        let mut account = account(EngineConfig {
+            idempotent: true,
+            ..Default::default()
+        });
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        account.handle_tx(withdrawal(1, dec!(2))).await.unwrap();
+        for event in [
+            Event::deposit(TxId(0), ClientId(0), dec!(6)),
+            Event::deposit(TxId(1), ClientId(0), dec!(2)),
+            withdrawal(0, dec!(1)),
+        ] {
+            let result = account.handle_tx(event).await;
+            assert!(matches!(result, Err(AccountingError::TransactionAlreadyExists(_))));
+        }
+        assert_eq!(dec!(3), account.available);
+    }
+    #[tokio::test]
+    async fn amounts_above_the_limit_are_rejected() {
+        let config = EngineConfig {
+            max_amount: Some(dec!(10)),
+            ..EngineConfig::default()
+        };
+        let mut account = account(config);
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(10)))
+            .await
+            .unwrap();
+        let result = account.handle_tx(Event::deposit(TxId(1), ClientId(0), dec!(10.01))).await;
+        assert!(matches!(
+            result,
+            Err(AccountingError::AmountExceedsLimit { tx_id: TxId(1), limit }) if limit == dec!(10)
+        ));
+        let result = account.handle_tx(Event::withdrawal(TxId(2), ClientId(0), dec!(11))).await;
+        assert!(matches!(
+            result,
+            Err(AccountingError::AmountExceedsLimit { tx_id: TxId(2), .. })
+        ));
+        assert_eq!(dec!(10), account.available);
+        assert!(!account.transactions.read().await.contains_key(&(ClientId(0), TxId(1))));
+    }
+    #[tokio::test]
+    async fn reverse_after_withdrawal_needs_funds() {
+        let mut account = account(EngineConfig::default());
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        account.handle_tx(withdrawal(1, dec!(3))).await.unwrap();
+        let reverse = |tx_id, new_tx_id| Event::Reverse {
+            client: ClientId(0),
+            tx_id: TxId(tx_id),
+            new_tx_id: TxId(new_tx_id),
+        };
+        let result = account.handle_tx(reverse(0, 2)).await;
+        assert!(matches!(result, Err(AccountingError::InsufficientFunds(ClientId(0)))));
+        account.handle_tx(reverse(1, 2)).await.unwrap();
+        assert_eq!(dec!(5), account.available);
+        let result = account.handle_tx(reverse(1, 3)).await;
+        assert!(matches!(result, Err(AccountingError::TransactionAlreadyReversed(TxId(1)))));
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        let result = account.handle_tx(reverse(0, 3)).await;
+        assert!(matches!(
+            result,
+            Err(AccountingError::TransactionIsAlreadyLocked { tx_id: TxId(0), .. })
+        ));
+    }
+    // The check of the held funds that panics only runs in debug builds
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn panic_during_reverse_is_rolled_back() {
+        let mut account = account(EngineConfig {
+            reject_negative_held: true,
+            ..Default::default()
+        });
+        assert!(account.apply(0, Event::deposit(TxId(0), ClientId(0), dec!(5))).await);
+        account.held = dec!(-1);
+        let reverse = Event::Reverse {
+            client: ClientId(0),
+            tx_id: TxId(0),
+            new_tx_id: TxId(1),
+        };
+        assert!(!account.apply(1, reverse.clone()).await);
+        assert_eq!((dec!(5), dec!(5)), (account.available, account.external));
+        assert!(account.reversed.is_empty());
+        assert!(!account.transactions.read().await.contains_key(&(ClientId(0), TxId(1))));
+        assert!(matches!(
+            account.rejections[..],
+            [(1, Event::Reverse { .. }, AccountingError::Panicked { client: ClientId(0), .. })]
+        ));
+        account.held = dec!(0);
+        assert!(account.apply(2, reverse).await);
+        assert_eq!(dec!(0), account.available);
+    }
+    #[tokio::test]
+    async fn resolve_more_than_held_is_rejected() {
+        let mut account = account(EngineConfig {
+            reject_negative_held: true,
+            ..Default::default()
+        });
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(10)))
+            .await
+            .unwrap();
+        account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await
+            .unwrap();
+        // As if earlier partial resolves had already released part of the dispute
+        account.held = dec!(4);
+        account.available = dec!(6);
+        let result = account
+            .handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::WouldGoNegative(ClientId(0)))));
+        let result = account
+            .handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::WouldGoNegative(ClientId(0)))));
+        assert_eq!((dec!(6), dec!(4)), (account.available, account.held));
+        assert!(!account.is_locked);
+        let state = account.transactions.read().await.get(&(ClientId(0), TxId(0))).unwrap().state;
+        assert_eq!(TxState::Disputed, state);
+    }
+    #[tokio::test]
+    async fn dispute_of_withdrawal_holds_the_withdrawn_amount() {
+        let mut account = account(EngineConfig {
+            reject_negative_held: true,
+            ..Default::default()
+        });
+        account.available = dec!(10);
+        account.handle_tx(withdrawal(0, dec!(4))).await.unwrap();
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!((dec!(6), dec!(4)), (account.available, account.held));
+        account.handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!((dec!(6), dec!(0)), (account.available, account.held));
+        assert_eq!(dec!(-4), account.external);
+
+        account.handle_tx(withdrawal(1, dec!(5))).await.unwrap();
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        assert_eq!((dec!(1), dec!(5)), (account.available, account.held));
+        account.handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        assert_eq!((dec!(6), dec!(0)), (account.available, account.held));
+        assert_eq!(dec!(-4), account.external);
+    }
+    #[tokio::test]
+    async fn dispute_error_refers_to_the_deposit_line() {
+        let mut account = account(EngineConfig::default());
+        let mut deposit = Transaction::new(TxId(0), ClientId(0), dec!(5));
+        deposit.line = Some(7);
+        account.handle_tx(Event::Deposit(deposit)).await.unwrap();
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        let result = account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(
+            result,
+            Err(AccountingError::TransactionIsAlreadyLocked {
+                tx_id: TxId(0),
+                line: Some(7)
+            })
+        ));
+    }
+    #[tokio::test]
+    async fn chargeback_freeze_takes_precedence_over_unfreeze() {
+        let mut account = account(EngineConfig::default());
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        account.handle_tx(Event::Freeze { client: ClientId(0) }).await.unwrap();
+        account.handle_tx(Event::Unfreeze { client: ClientId(0) }).await.unwrap();
+        assert!(!account.is_locked);
+
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        account.handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        let result = account.handle_tx(Event::Unfreeze { client: ClientId(0) }).await;
+        assert!(matches!(result, Err(AccountingError::CannotUnfreezeChargedBack(ClientId(0)))));
+        assert!(account.is_locked);
+        account.handle_tx(Event::Reinstate { client: ClientId(0) }).await.unwrap();
+        assert!(!account.is_locked);
+    }
+    #[tokio::test]
+    async fn fee_sweep_records_the_fee() {
+        let mut account = account(EngineConfig::default());
+        let sweep = Event::FeeSweep { amount: dec!(2) };
+        let result = account.handle_tx(sweep.clone()).await;
+        assert!(matches!(result, Err(AccountingError::InsufficientFunds(ClientId(0)))));
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        account.handle_tx(sweep).await.unwrap();
+        assert_eq!(dec!(3), account.available);
+        assert_eq!(vec![dec!(2)], account.fees);
+    }
+    #[tokio::test]
+    async fn clamped_dispute_holds_only_the_available_funds() {
+        for clamp in [false, true] {
+            let mut account = account(EngineConfig {
+                dispute_clamp: clamp,
+                ..Default::default()
+            });
+            account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(100))).await.unwrap();
+            account.handle_tx(withdrawal(1, dec!(80))).await.unwrap();
+            account
+                .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+                .await
+                .unwrap();
+            if clamp {
+                assert_eq!((dec!(0), dec!(20)), (account.available, account.held));
+                assert_eq!(Some(&dec!(80)), account.shortfall.get(&TxId(0)));
+            } else {
+                assert_eq!((dec!(-80), dec!(100)), (account.available, account.held));
+            }
+            account
+                .handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(0) })
+                .await
+                .unwrap();
+            assert_eq!((dec!(20), dec!(0)), (account.available, account.held));
+            assert!(account.shortfall.is_empty());
+        }
+    }
+    #[tokio::test]
+    async fn other_clients_transaction_isnt_found() {
+        let mut account = account(EngineConfig::default());
+        let mut foreign = Transaction::new(TxId(1), ClientId(1), dec!(7));
+        foreign.state = TxState::Disputed;
+        account.transactions.write().await.insert((ClientId(1), TxId(1)), foreign);
+        for event in [
+            Event::Dispute { client: ClientId(0), tx_id: TxId(1) },
+            Event::Resolve { client: ClientId(0), tx_id: TxId(1) },
+            Event::Chargeback { client: ClientId(0), tx_id: TxId(1) },
+        ] {
+            let result = account.handle_tx(event).await;
+            assert!(matches!(result, Err(AccountingError::TransactionDoesntExist(TxId(1)))));
+        }
+        assert_eq!((dec!(0), dec!(0)), (account.available, account.held));
+        assert!(!account.is_locked);
+    }
+    #[tokio::test]
+    async fn undisputed_transaction_isnt_settled() {
+        let mut account = account(EngineConfig::default());
+        account
+            .handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5)))
+            .await
+            .unwrap();
+        // Marked as disputed without this account holding its funds
+        let mut marked = Transaction::new(TxId(1), ClientId(0), dec!(3));
+        marked.state = TxState::Disputed;
+        account.transactions.write().await.insert((ClientId(0), TxId(1)), marked);
+        for event in [
+            Event::Resolve { client: ClientId(0), tx_id: TxId(1) },
+            Event::Chargeback { client: ClientId(0), tx_id: TxId(1) },
+        ] {
+            let result = account.handle_tx(event).await;
+            assert!(matches!(
+                result,
+                Err(AccountingError::TransactionIsNotDisputed { tx_id: TxId(1), .. })
+            ));
+        }
+        assert_eq!((dec!(5), dec!(0)), (account.available, account.held));
+        assert!(!account.is_locked);
+        assert!(account.disputes.is_empty());
+    }
+    #[tokio::test]
+    async fn transaction_state_transitions() {
+        let mut account = account(EngineConfig::default());
+        async fn state(account: &Account) -> TxState {
+            account.transactions.read().await.get(&(ClientId(0), TxId(0))).unwrap().state
+        }
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        assert_eq!(TxState::Normal, state(&account).await);
+        let result = account
+            .handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::TransactionIsNotDisputed { .. })));
+        let result = account
+            .handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::TransactionIsNotDisputed { .. })));
+
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!(TxState::Disputed, state(&account).await);
+        let result = account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::TransactionIsAlreadyLocked { .. })));
+        account.handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!(TxState::Normal, state(&account).await);
+
+        account.handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        account.handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!(TxState::ChargedBack, state(&account).await);
+        for event in [
+            Event::Dispute { client: ClientId(0), tx_id: TxId(0) },
+            Event::Resolve { client: ClientId(0), tx_id: TxId(0) },
+            Event::Chargeback { client: ClientId(0), tx_id: TxId(0) },
+        ] {
+            let result = account.handle_tx(event).await;
+            assert!(matches!(result, Err(AccountingError::TransactionAlreadyChargedBack(TxId(0)))));
+        }
+        assert_eq!(TxState::ChargedBack, state(&account).await);
+    }
+    #[tokio::test]
+    async fn partial_dispute_of_half_a_deposit() {
+        let mut account = account(EngineConfig::default());
+        let partial = |amount| Event::PartialDispute {
+            client: ClientId(0),
+            tx_id: TxId(0),
+            amount,
+        };
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(10))).await.unwrap();
+        account.handle_tx(partial(dec!(5))).await.unwrap();
+        assert_eq!((dec!(5), dec!(5)), (account.available, account.held));
+        let result = account
+            .handle_tx(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await;
+        assert!(matches!(result, Err(AccountingError::TransactionIsAlreadyLocked { .. })));
+        account.handle_tx(Event::Resolve { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!((dec!(10), dec!(0)), (account.available, account.held));
+
+        account.handle_tx(partial(dec!(4))).await.unwrap();
+        account.handle_tx(partial(dec!(4))).await.unwrap();
+        assert_eq!((dec!(2), dec!(8)), (account.available, account.held));
+        let result = account.handle_tx(partial(dec!(3))).await;
+        assert!(matches!(result, Err(AccountingError::InvalidAmount)));
+        account.handle_tx(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        assert_eq!((dec!(2), dec!(0)), (account.available, account.held));
+        assert_eq!(dec!(2), account.external);
+    }
+    #[tokio::test]
+    async fn overflowing_deposit_is_rejected() {
+        let mut account = account(EngineConfig::default());
+        let amount = Decimal::MAX - dec!(1);
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), amount)).await.unwrap();
+        let result = account.handle_tx(Event::deposit(TxId(1), ClientId(0), amount)).await;
+        assert!(matches!(result, Err(AccountingError::AmountOverflow(ClientId(0)))));
+        assert_eq!((amount, amount), (account.available, account.external));
+        assert!(!account.transactions.read().await.contains_key(&(ClientId(0), TxId(1))));
+    }
+    #[tokio::test]
+    async fn first_deposit_fixes_the_currency() {
+        let mut account = account(EngineConfig::default());
+        let in_currency = |tx: Event, code: &str| match tx {
+            Event::Deposit(tx) => Event::Deposit(Transaction {
+                currency: Some(code.parse().unwrap()),
+                ..tx
+            }),
+            Event::Withdrawal(tx) => Event::Withdrawal(Transaction {
+                currency: Some(code.parse().unwrap()),
+                ..tx
+            }),
+            _ => tx,
+        };
+        account.handle_tx(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        assert_eq!(None, account.currency);
+        let eur = in_currency(Event::deposit(TxId(1), ClientId(0), dec!(5)), "eur");
+        account.handle_tx(eur).await.unwrap();
+        assert_eq!(Some("EUR".parse().unwrap()), account.currency);
+        account.handle_tx(in_currency(withdrawal(2, dec!(1)), "EUR")).await.unwrap();
+        account.handle_tx(withdrawal(3, dec!(1))).await.unwrap();
+        for mismatch in [
+            in_currency(Event::deposit(TxId(4), ClientId(0), dec!(1)), "USD"),
+            in_currency(withdrawal(5, dec!(1)), "USD"),
+        ] {
+            let result = account.handle_tx(mismatch).await;
+            assert!(matches!(
+                result,
+                Err(AccountingError::CurrencyMismatch { client: ClientId(0), expected, found })
+                    if expected.to_string() == "EUR" && found.to_string() == "USD"
+            ));
+        }
+        assert_eq!(dec!(8), account.available);
+    }
+    #[tokio::test]
+    async fn queued_withdrawals_clear_after_deposits() {
+        let mut account = account(EngineConfig {
+            queue_withdrawals: true,
+            ..Default::default()
+        });
+        account.handle_tx(withdrawal(0, dec!(5))).await.unwrap();
+        account.handle_tx(withdrawal(1, dec!(1))).await.unwrap();
+        account.handle_tx(withdrawal(2, dec!(100))).await.unwrap();
+        assert_eq!(3, account.pending_withdrawals.len());
+        account.handle_tx(Event::deposit(TxId(3), ClientId(0), dec!(4))).await.unwrap();
+        assert_eq!(dec!(4), account.available);
+        account.handle_tx(Event::deposit(TxId(4), ClientId(0), dec!(4))).await.unwrap();
+        assert_eq!(dec!(2), account.available);
+        assert_eq!(dec!(2), account.external);
+        assert!(account.transactions.read().await.contains_key(&(ClientId(0), TxId(1))));
+
+        let unaffordable = account.drain_pending_withdrawals();
+        assert_eq!(vec![TxId(2)], unaffordable.iter().map(|(_, tx)| tx.id).collect::<Vec<_>>());
+        assert!(account.pending_withdrawals.is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::config::EventCounters;
+use crate::engine::{AccountingResult, Amount, ClientId};
+use crate::guard::HashGuard;
+use crate::output::{convert_output, write_output, OutputOptions};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+/// Summary of a run that lets consumers check the integrity of its output
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct Certificate {
+    pub events: u64,
+    pub rejected: u64,
+    /// Deposits minus withdrawals, chargebacks, fees and reversals over all accounts
+    pub net: Amount,
+    pub frozen_accounts: usize,
+    /// sha256 of the csv output with all columns, which is sorted by client
+    pub output_sha256: String,
+}
+impl Certificate {
+    pub fn new(
+        counters: &EventCounters,
+        result: &BTreeMap<ClientId, AccountingResult>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut output = vec![];
+        write_output(
+            &mut output,
+            convert_output(result.clone(), OutputOptions::default()),
+        )?;
+        Ok(Certificate {
+            events: counters.received.load(Ordering::SeqCst),
+            rejected: counters.rejected.load(Ordering::SeqCst),
+            net: result
+                .values()
+                .map(|account| account.external)
+                .sum::<Amount>()
+                .normalize(),
+            frozen_accounts: result.values().filter(|account| account.locked).count(),
+            output_sha256: HashGuard::digest(&output),
+        })
+    }
+    /// Writes the certificate as a json object
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::certificate::Certificate;
+    use crate::config::EventCounters;
+    use crate::engine::{AccountingEngine, ClientId, TxId};
+    use crate::transactions::Event;
+    use rust_decimal_macros::dec;
+    use std::sync::Arc;
+
+    async fn certify(events: Vec<Event>) -> Certificate {
+        let counters = Arc::new(EventCounters::default());
+        let (engine, sender) = AccountingEngine::builder()
+            .count_events(counters.clone())
+            .build();
+        for event in events {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        Certificate::new(&counters, &result).unwrap()
+    }
+
+    #[tokio::test]
+    async fn certificate_summarizes_the_run() {
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(1), dec!(10)),
+            Event::withdrawal(TxId(2), ClientId(1), dec!(4)),
+            Event::withdrawal(TxId(3), ClientId(1), dec!(100)),
+            Event::deposit(TxId(4), ClientId(2), dec!(5)),
+            Event::Dispute {
+                client: ClientId(2),
+                tx_id: TxId(4),
+            },
+            Event::Chargeback {
+                client: ClientId(2),
+                tx_id: TxId(4),
+            },
+            Event::deposit(TxId(5), ClientId(2), dec!(1)),
+        ];
+        let certificate = certify(events.clone()).await;
+        assert_eq!(7, certificate.events);
+        assert_eq!(2, certificate.rejected);
+        assert_eq!(dec!(6), certificate.net);
+        assert_eq!(1, certificate.frozen_accounts);
+        assert_eq!(certificate, certify(events.clone()).await);
+
+        let mut changed = events;
+        changed[1] = Event::withdrawal(TxId(2), ClientId(1), dec!(3));
+        let changed = certify(changed).await;
+        assert_ne!(certificate.output_sha256, changed.output_sha256);
+    }
+}
@@ -0,0 +1,241 @@
+use accounting_engine::config::Rounding;
+use accounting_engine::output::{OutputFormat, DEFAULT_OUTPUT_SCALE};
+use accounting_engine::ClientId;
+use rust_decimal::RoundingStrategy;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// What `--scale` and `--rounding` start from
+const DEFAULT_ROUNDING: Rounding = Rounding {
+    scale: DEFAULT_OUTPUT_SCALE,
+    strategy: RoundingStrategy::MidpointNearestEven,
+};
+
+/// How csv rows are turned into events
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputOptions {
+    /// Accept `transaction` rows whose amount sign picks deposit (+) or withdrawal (-)
+    pub signed_amounts: bool,
+    /// Abort once this many rows couldn't be read instead of skipping them
+    pub max_errors: Option<usize>,
+    /// Reject amounts with more decimal places
+    pub max_scale: Option<u32>,
+    /// Round amounts to `max_scale` instead of rejecting them
+    pub round_scale: bool,
+    /// The rows of every client form one contiguous block
+    pub sorted_by_client: bool,
+    /// Buffer all rows and process them in the order of their timestamps
+    pub order_by_time: bool,
+    /// Drop the rows of the clients this filter doesn't let through
+    pub clients: Option<ClientFilter>,
+    pub format: InputFormat,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// A json array of records or one record per line, with the fields of the csv columns
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientFilter {
+    Only(BTreeSet<ClientId>),
+    Exclude(BTreeSet<ClientId>),
+}
+impl ClientFilter {
+    pub fn allows(&self, client: ClientId) -> bool {
+        match self {
+            ClientFilter::Only(clients) => clients.contains(&client),
+            ClientFilter::Exclude(clients) => !clients.contains(&client),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Options {
+    /// The input files or globs, processed in order as one ledger.
+    /// The standard input is read if there are none or for `-`.
+    pub inputs: Vec<String>,
+    /// Sidecar file with the hashes of already processed inputs
+    pub dedup: Option<PathBuf>,
+    /// `verify <input>... <expected>`: compare the results against an expected output file
+    pub expected: Option<String>,
+    /// Write every accepted event to stderr in the order it was applied
+    pub echo_events: bool,
+    /// Write the result of every client into its own file in this directory
+    pub split_by_client: Option<PathBuf>,
+    /// Keep deposits pending until this many later events of the client were handled
+    pub clearing_delay: usize,
+    /// Leave the `total` column out of the output
+    pub no_total: bool,
+    /// Add a column with the number of disputed transactions of every client
+    pub disputed_count: bool,
+    /// Truncate amounts to four decimal places instead of writing them in full
+    pub truncate: bool,
+    /// Round amounts to a fixed number of decimal places
+    pub scale: Option<Rounding>,
+    /// Write the clients in the order they first appeared instead of sorted by id
+    pub first_seen_order: bool,
+    /// Write a summary of the run with a hash of its output to this file
+    pub certificate: Option<PathBuf>,
+    /// Fail if the totals don't add up to the net of the funds that entered and left the system
+    pub verify_conservation: bool,
+    /// Serve the connections to this unix socket instead of reading a file
+    pub uds: Option<PathBuf>,
+    /// Print the expected columns and an example row of every type instead of processing
+    pub print_schema: bool,
+    pub input_options: InputOptions,
+    pub format: OutputFormat,
+}
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.into_iter().peekable();
+        let verify = args.next_if(|arg| arg == "verify").is_some();
+        let mut positional = vec![];
+        let mut table = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dedup" => {
+                    let path = args
+                        .next()
+                        .ok_or("--dedup expects a path to a sidecar file")?;
+                    options.dedup = Some(path.into());
+                }
+                "--echo-events" => options.echo_events = true,
+                "--no-total" => options.no_total = true,
+                "--disputed-count" => options.disputed_count = true,
+                "--truncate" => options.truncate = true,
+                "--scale" => {
+                    let scale = args
+                        .next()
+                        .and_then(|scale| scale.parse().ok())
+                        .ok_or("--scale expects a number of decimal places")?;
+                    options.scale.get_or_insert(DEFAULT_ROUNDING).scale = scale;
+                }
+                "--rounding" => {
+                    options.scale.get_or_insert(DEFAULT_ROUNDING).strategy =
+                        match args.next().as_deref() {
+                            Some("half-even") => RoundingStrategy::MidpointNearestEven,
+                            Some("half-up") => RoundingStrategy::MidpointAwayFromZero,
+                            Some("down") => RoundingStrategy::ToZero,
+                            _ => {
+                                return Err("--rounding expects one of half-even, half-up, down"
+                                    .to_string())
+                            }
+                        };
+                }
+                "--first-seen-order" => options.first_seen_order = true,
+                "--print-schema" => options.print_schema = true,
+                "--verify-conservation" => options.verify_conservation = true,
+                "--signed-amounts" => options.input_options.signed_amounts = true,
+                "--split-by-client" => {
+                    let dir = args.next().ok_or("--split-by-client expects a directory")?;
+                    options.split_by_client = Some(dir.into());
+                }
+                "--format" | "--output" => {
+                    options.format = match args.next().as_deref() {
+                        Some("csv") => OutputFormat::Csv,
+                        Some("sql") => OutputFormat::Sql {
+                            table: String::new(),
+                        },
+                        Some("ndjson") => OutputFormat::Ndjson,
+                        Some("json") => OutputFormat::Json,
+                        _ => return Err(format!("{} expects one of csv, sql, ndjson, json", arg)),
+                    };
+                }
+                "--input-format" => {
+                    options.input_options.format = match args.next().as_deref() {
+                        Some("csv") => InputFormat::Csv,
+                        Some("json") => InputFormat::Json,
+                        _ => return Err("--input-format expects one of csv, json".to_string()),
+                    };
+                }
+                "--table" => table = Some(args.next().ok_or("--table expects a table name")?),
+                "--clearing-delay" => {
+                    options.clearing_delay = args
+                        .next()
+                        .and_then(|delay| delay.parse().ok())
+                        .ok_or("--clearing-delay expects a number of events")?;
+                }
+                "--max-input-scale" => {
+                    options.input_options.max_scale = Some(
+                        args.next()
+                            .and_then(|scale| scale.parse().ok())
+                            .ok_or("--max-input-scale expects a number of decimal places")?,
+                    );
+                }
+                "--sorted-by-client" => options.input_options.sorted_by_client = true,
+                "--round-input-scale" => options.input_options.round_scale = true,
+                "--order-by-time" => options.input_options.order_by_time = true,
+                "--only-clients" | "--exclude-clients" => {
+                    if options.input_options.clients.is_some() {
+                        return Err("Only one of --only-clients, --exclude-clients".to_string());
+                    }
+                    let clients = args
+                        .next()
+                        .and_then(|list| list.split(',').map(|id| id.trim().parse().ok()).collect())
+                        .ok_or(format!("{} expects a comma separated list of clients", arg))?;
+                    options.input_options.clients = Some(match arg.as_str() {
+                        "--only-clients" => ClientFilter::Only(clients),
+                        _ => ClientFilter::Exclude(clients),
+                    });
+                }
+                "--certificate" => {
+                    let path = args.next().ok_or("--certificate expects a path")?;
+                    options.certificate = Some(path.into());
+                }
+                "--uds" => {
+                    let path = args.next().ok_or("--uds expects a path to a socket")?;
+                    options.uds = Some(path.into());
+                }
+                "--max-errors" => {
+                    options.input_options.max_errors = Some(
+                        args.next()
+                            .and_then(|max| max.parse().ok())
+                            .filter(|max| *max > 0)
+                            .ok_or("--max-errors expects a positive number of rows")?,
+                    );
+                }
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => positional.push(arg),
+            }
+        }
+        match &mut options.format {
+            OutputFormat::Sql { table: name } => *name = table.unwrap_or("accounts".to_string()),
+            _ if table.is_some() => return Err("--table requires --format sql".to_string()),
+            _ => {}
+        }
+        if options.truncate && options.scale.is_some() {
+            return Err("--truncate can't be combined with --scale or --rounding".to_string());
+        }
+        if options.uds.is_some() {
+            // These only apply to a run over input files
+            let ignored = [
+                ("--certificate", options.certificate.is_some()),
+                ("--dedup", options.dedup.is_some()),
+                ("--echo-events", options.echo_events),
+                ("--first-seen-order", options.first_seen_order),
+                ("--split-by-client", options.split_by_client.is_some()),
+                ("--verify-conservation", options.verify_conservation),
+                ("verify", verify),
+            ];
+            if let Some((option, _)) = ignored.iter().find(|(_, set)| *set) {
+                return Err(format!("--uds can't be combined with {}", option));
+            }
+        }
+        if options.input_options.round_scale && options.input_options.max_scale.is_none() {
+            return Err("--round-input-scale requires --max-input-scale".to_string());
+        }
+        if verify {
+            if positional.len() < 2 {
+                return Err("Usage: verify <input.csv>... <expected_output.csv>".to_string());
+            }
+            options.expected = positional.pop();
+        }
+        options.inputs = positional;
+        Ok(options)
+    }
+}
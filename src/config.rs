@@ -0,0 +1,147 @@
+use crate::engine::{Amount, ClientId};
+use crate::metrics::Metrics;
+use crate::transactions::{Event, LifecycleEvent};
+use rust_decimal::RoundingStrategy;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Options shared by the engine and all of its accounts.
+#[derive(Clone, Debug, Default)]
+pub struct EngineConfig {
+    /// Accept withdrawals of exactly zero as a no-op instead of rejecting them
+    pub allow_zero_withdrawals: bool,
+    /// Receives account lifecycle events as they occur
+    pub lifecycle: Option<UnboundedSender<LifecycleEvent>>,
+    /// Receives every accepted event with its position in the input stream
+    pub echo: Option<UnboundedSender<(u64, Event)>>,
+    /// Number of later events of the same client after which a deposit becomes available.
+    /// Until then it is pending.
+    pub clearing_delay: usize,
+    /// Reject operations that would make an account's held funds negative
+    pub reject_negative_held: bool,
+    /// Limits the funds an account can hold in dispute
+    pub held_cap: Option<HeldCap>,
+    /// Limits the number of events a single client can send
+    pub event_limit: Option<EventLimit>,
+    /// The events of every client arrive in one contiguous block
+    pub sorted_by_client: bool,
+    /// Disputes hold at most the available funds instead of driving them negative
+    pub dispute_clamp: bool,
+    /// Rounds the balances of an account after every operation
+    pub rounding: Option<Rounding>,
+    /// Counts the received and rejected events
+    pub counters: Option<Arc<EventCounters>>,
+    /// Number of events the channels to the engine and to every account can queue.
+    /// Defaults to [`DEFAULT_CHANNEL_CAPACITY`].
+    pub channel_capacity: Option<usize>,
+    /// Withdrawals the account can't afford wait for later deposits instead of being rejected
+    pub queue_withdrawals: bool,
+    /// Receives the clients in the order their accounts were created
+    pub first_seen: Option<Arc<Mutex<Vec<ClientId>>>>,
+    /// Every account keeps a log of the events it applied in
+    /// [`AccountingResult::log`](crate::engine::AccountingResult::log)
+    pub applied_log: bool,
+    /// What a chargeback does if it leaves the available funds negative
+    pub chargeback_policy: ChargebackPolicy,
+    /// Counters for monitoring, see [`Metrics`]
+    pub metrics: Option<Arc<Metrics>>,
+    /// Only checks the events for errors and leaves all balances at zero
+    pub dry_run: bool,
+    /// Deposits and withdrawals of larger amounts are rejected
+    pub max_amount: Option<Amount>,
+    /// How far withdrawals, fee sweeps, transfers and reversals can take the available funds
+    /// below zero
+    pub overdraft_limit: Amount,
+    /// A deposit or withdrawal that repeats a recorded one with the same id is skipped
+    /// instead of rejected
+    pub idempotent: bool,
+    /// A deposit that arrives already disputed lands in the held funds instead of being
+    /// rejected, see [`TxState::Disputed`](crate::transactions::TxState::Disputed)
+    pub locked_deposits: bool,
+    /// Called once for every account that a chargeback freezes
+    pub on_chargeback_freeze: Option<FreezeCallback>,
+}
+/// Events queued per channel unless [`EngineConfig::channel_capacity`] is set
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+impl EngineConfig {
+    pub fn channel_capacity(&self) -> usize {
+        self.channel_capacity.unwrap_or(DEFAULT_CHANNEL_CAPACITY)
+    }
+    pub fn count_received(&self) {
+        if let Some(counters) = &self.counters {
+            counters.received.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    pub fn count_rejected(&self) {
+        if let Some(counters) = &self.counters {
+            counters.rejected.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    /// Increments the `counter` of the metrics, if there are any
+    pub fn count(&self, counter: fn(&Metrics) -> &AtomicU64) {
+        if let Some(metrics) = &self.metrics {
+            counter(metrics).fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Events received by the engine and events rejected by the engine or an account.
+/// A [`Event::FeeSweep`] is received once but can be rejected by every account.
+#[derive(Debug, Default)]
+pub struct EventCounters {
+    pub received: AtomicU64,
+    pub rejected: AtomicU64,
+}
+
+/// Rounds amounts to `scale` decimal places with `strategy`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rounding {
+    pub scale: u32,
+    pub strategy: RoundingStrategy,
+}
+impl Rounding {
+    pub fn round(&self, amount: Amount) -> Amount {
+        amount.round_dp_with_strategy(self.scale, self.strategy)
+    }
+}
+
+/// Events of a client beyond the first `max` are rejected.
+/// With `freeze` the account is also frozen once it exceeds the limit.
+#[derive(Clone, Debug)]
+pub struct EventLimit {
+    pub max: usize,
+    pub freeze: bool,
+}
+
+/// How a chargeback treats available funds that are negative after it, which happens if
+/// the charged back deposit was already withdrawn
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChargebackPolicy {
+    /// The client owes the negative available funds
+    #[default]
+    AllowNegative,
+    /// The debt is written off, which counts as funds entering the account
+    ClampToZero,
+    /// The chargeback is rejected with
+    /// [`AccountingError::InsufficientFunds`](crate::errors::AccountingError::InsufficientFunds)
+    /// and the transaction stays disputed
+    Reject,
+}
+
+/// Receives the client whose unlocked account was frozen by a chargeback
+#[derive(Clone)]
+pub struct FreezeCallback(pub Arc<dyn Fn(ClientId) + Send + Sync>);
+impl Debug for FreezeCallback {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FreezeCallback")
+    }
+}
+
+/// Disputed funds beyond `cap` are held by the `suspense_account` instead of the client.
+#[derive(Clone, Debug)]
+pub struct HeldCap {
+    pub cap: Amount,
+    pub suspense_account: ClientId,
+}
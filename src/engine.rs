@@ -1,577 +1,2402 @@
-use crate::account::Account;
-use crate::transactions::{Event, Transaction};
+use crate::account::{Account, AccountMessage, AccountSnapshot};
+use crate::config::{
+    ChargebackPolicy, EngineConfig, EventCounters, EventLimit, FreezeCallback, HeldCap, Rounding,
+};
+use crate::errors::{log_rejection, AccountingError, EngineError};
+use crate::metrics::Metrics;
+use crate::sequential;
+use crate::transactions::{AppliedEvent, Event, LifecycleEvent, Transaction};
 use std::collections::BTreeMap;
 use std::error::Error;
-use std::sync::Arc;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::sync::RwLock;
-use tokio::task::JoinHandle;
+use std::fmt::{Debug, Display, Formatter};
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender};
+use tokio::sync::{oneshot, RwLock};
+use tokio::task::{JoinError, JoinHandle};
+use tracing::Instrument;
 use rust_decimal::prelude::*;
-use rust_decimal_macros::dec;
 
-pub type ClientId = u16;
-pub type TxId = u32;
+/// Identifies the account of a client. Ids of clients and transactions are different types,
+/// so one can't be passed for the other:
+///
+/// ```compile_fail
+/// use accounting_engine::{ClientId, Event, TxId};
+/// use rust_decimal_macros::dec;
+///
+/// let (client, tx_id) = (ClientId(1), TxId(2));
+/// // The transaction id comes first
+/// Event::deposit(client, tx_id, dec!(1));
+/// ```
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct ClientId(pub u16);
+/// Identifies a transaction among those of its client
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct TxId(pub u32);
 pub type Amount = Decimal;
+/// Formats ids as their numbers, so logs and error messages read like the input
+impl Debug for ClientId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+impl Display for ClientId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl FromStr for ClientId {
+    type Err = ParseIntError;
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        id.parse().map(ClientId)
+    }
+}
+impl Debug for TxId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+impl Display for TxId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl FromStr for TxId {
+    type Err = ParseIntError;
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        id.parse().map(TxId)
+    }
+}
 
 pub struct AccountingEngine {
-    incoming_tx: UnboundedReceiver<Event>,
-    transactions: Arc<RwLock<BTreeMap<TxId, Transaction>>>,
-    tx_to_accounts: BTreeMap<ClientId, UnboundedSender<Event>>,
-    result: Vec<JoinHandle<Account>>,
+    incoming_tx: Receiver<Event>,
+    /// The deposits and withdrawals of all clients. Every client has its own transaction ids.
+    transactions: Arc<RwLock<BTreeMap<(ClientId, TxId), Transaction>>>,
+    tx_to_accounts: BTreeMap<ClientId, Sender<AccountMessage>>,
+    result: BTreeMap<ClientId, (JoinHandle<Account>, Arc<AtomicBool>)>,
+    /// Results of purged clients, whose later events are dropped
+    purged: BTreeMap<ClientId, AccountingResult>,
+    /// Results of accounts closed by [`AccountingEngine::finalize`] or because their block
+    /// of sorted events ended
+    finished: BTreeMap<ClientId, AccountingResult>,
+    config: Arc<EngineConfig>,
+    suspense_held: Arc<Mutex<Amount>>,
+    /// Number of events received so far
+    sequence: u64,
+    /// Rejected events with the position in the input stream they were rejected at
+    rejections: Vec<(u64, Event, AccountingError)>,
+    /// The aggregates of the accounts that were closed so far
+    report: ReconciliationReport,
+    /// Clients whose account tasks panicked
+    panicked: Vec<ClientId>,
 }
 impl AccountingEngine {
-    pub fn new() -> (Self, UnboundedSender<Event>) {
-        let (sender, receiver) = unbounded_channel();
-        (
-            AccountingEngine {
-                incoming_tx: receiver,
-                transactions: Arc::new(Default::default()),
-                tx_to_accounts: Default::default(),
-                result: vec![],
-            },
-            sender,
-        )
-    }
-    pub async fn process_txs(mut self) -> BTreeMap<ClientId, AccountingResult> {
-        loop {
-            if let Some(tx) = self.incoming_tx.recv().await {
-                if let Err(_e) = self.handle_tx(tx).await {
-                    // eprintln!("{:?}", e);
-                }
-            } else {
-                break;
+    pub fn new() -> (Self, Sender<Event>) {
+        Self::builder().build()
+    }
+    pub fn builder() -> AccountingEngineBuilder {
+        AccountingEngineBuilder::default()
+    }
+    /// Handles the events until the sender is dropped and returns the output of all accounts.
+    /// The output only depends on the order of the events of each client,
+    /// never on how the tasks of the accounts were scheduled.
+    /// An event whose handling panics is rejected with [`AccountingError::Panicked`]
+    /// and the other events of the client are still applied.
+    /// Fails instead of leaving out an account whose task panicked otherwise.
+    pub async fn process_txs(mut self) -> Result<EngineOutput, EngineError> {
+        while let Some(tx) = self.incoming_tx.recv().await {
+            if let Err(e) = self.handle_tx(tx).await {
+                tracing::debug!(error = %e, "event not applied");
+                self.config.count_rejected();
             }
         }
+        let results = self.finalize().await?;
+        Ok(EngineOutput::new(
+            results,
+            std::mem::take(&mut self.rejections),
+            std::mem::take(&mut self.report),
+        ))
+    }
+    /// Applies the events that were already sent, closes all accounts and returns their results.
+    /// Unlike [`AccountingEngine::process_txs`] the engine stays around to be inspected.
+    pub async fn finalize(&mut self) -> Result<BTreeMap<ClientId, AccountingResult>, EngineError> {
+        self.route_pending().await;
         self.tx_to_accounts = Default::default();
-        let mut result = BTreeMap::default();
-        for handle in self.result {
-            if let Ok(account) = handle.await {
-                result.insert(
-                    account.id,
-                    AccountingResult {
-                        available: account.available,
-                        held: account.held,
-                        total: account.available + account.held,
-                        locked: account.is_locked,
-                    },
-                );
-            } else {
-                // eprintln!("there was an error awaiting the account join handles");
+        // The accounts finish concurrently, but are closed in the order of their ids
+        // so that their rejections are too
+        let (joined, mut closing) = unbounded_channel();
+        for (client, (handle, _)) in std::mem::take(&mut self.result) {
+            let joined = joined.clone();
+            tokio::spawn(async move {
+                let _ = joined.send((client, handle.await));
+            });
+        }
+        drop(joined);
+        let mut accounts = BTreeMap::new();
+        while let Some((client, account)) = closing.recv().await {
+            match account {
+                Ok(account) => {
+                    accounts.insert(account.id, account);
+                }
+                Err(e) => self.failed(client, e),
+            }
+        }
+        for (id, account) in accounts {
+            let result = self.close(account);
+            self.finished.insert(id, result);
+        }
+        let mut result = self.purged.clone();
+        result.extend(self.finished.clone());
+        if let Some(client) = self.panicked.iter().min() {
+            return Err(EngineError::AccountPanicked(*client));
+        }
+        finish_results(&self.config, &self.suspense_held, &mut result);
+        Ok(result)
+    }
+    /// Records that the task of the account of `client` ended with `error`
+    fn failed(&mut self, client: ClientId, error: JoinError) {
+        tracing::error!(client = %client, error = %error, "account failed");
+        self.panicked.push(client);
+    }
+    /// The number of transactions currently recorded across all accounts.
+    /// Charged back transactions stay recorded, purged clients' transactions are evicted.
+    pub async fn transaction_count(&self) -> usize {
+        self.transactions.read().await.len()
+    }
+    /// The current balances of those `clients` that have an account, queried from all
+    /// accounts at once. Events that were already sent are applied first.
+    pub async fn snapshot_many(
+        &mut self,
+        clients: &[ClientId],
+    ) -> BTreeMap<ClientId, AccountingResult> {
+        self.route_pending().await;
+        let mut snapshot = BTreeMap::new();
+        let mut replies = vec![];
+        for client in clients {
+            if let Some(sender) = self.tx_to_accounts.get(client) {
+                let (reply, receiver) = oneshot::channel();
+                if sender.send(AccountMessage::Snapshot(reply)).await.is_ok() {
+                    replies.push((*client, receiver));
+                }
+            } else if let Some(result) = self.purged.get(client).or(self.finished.get(client)) {
+                snapshot.insert(*client, result.clone());
+            }
+        }
+        for (client, receiver) in replies {
+            if let Ok(result) = receiver.await {
+                snapshot.insert(client, result);
+            }
+        }
+        snapshot
+    }
+    /// Routes the events that were already sent without waiting for the sender to close
+    async fn route_pending(&mut self) {
+        while let Ok(tx) = self.incoming_tx.try_recv() {
+            if let Err(e) = self.handle_tx(tx).await {
+                tracing::debug!(error = %e, "event not applied");
+                self.config.count_rejected();
             }
         }
-        result
+    }
+    /// Stops the account of `client`, discards its queued events and evicts its transactions.
+    /// Returns the balance of the events it applied until then. Later events of the
+    /// client are dropped and the returned balance is part of the final results.
+    pub async fn purge_client(&mut self, client: ClientId) -> Option<AccountingResult> {
+        self.route_pending().await;
+        let (handle, purged) = self.result.remove(&client)?;
+        purged.store(true, Ordering::SeqCst);
+        self.tx_to_accounts.remove(&client);
+        let account = match handle.await {
+            Ok(account) => account,
+            Err(e) => {
+                self.failed(client, e);
+                return None;
+            }
+        };
+        let result = self.close(account);
+        self.transactions.write().await.retain(|_, tx| tx.client != client);
+        self.purged.insert(client, result.clone());
+        Some(result)
+    }
+    /// Closes the accounts of every client but `client` and keeps their results
+    async fn finish_others(&mut self, client: ClientId) {
+        let others: Vec<_> = self.result.keys().filter(|id| **id != client).copied().collect();
+        for other in others {
+            self.tx_to_accounts.remove(&other);
+            let (handle, _) = self.result.remove(&other).unwrap();
+            match handle.await {
+                Ok(account) => {
+                    let result = self.close(account);
+                    self.finished.insert(other, result);
+                }
+                Err(e) => self.failed(other, e),
+            }
+        }
+    }
+    /// The result of an account that stopped, keeping the events it rejected
+    fn close(&mut self, mut account: Account) -> AccountingResult {
+        self.rejections.append(&mut account.rejections);
+        self.report.add(&account);
+        account.result()
+    }
+    /// Records the rejection of `tx`, which precedes the next event that is routed
+    fn reject(&mut self, tx: Event, error: AccountingError) -> Box<dyn Error> {
+        log_rejection(&tx, &error);
+        self.rejections.push((self.sequence, tx, error.clone()));
+        error.into()
+    }
+    /// Sends `tx` to every open account under one sequence number
+    async fn sweep(&mut self, tx: Event) -> Result<(), Box<dyn Error>> {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        for sender in self.tx_to_accounts.values() {
+            sender.send(AccountMessage::Event(sequence, tx.clone())).await?;
+        }
+        Ok(())
     }
     async fn handle_tx(&mut self, tx: Event) -> Result<(), Box<dyn Error>> {
-        let client = match tx.clone() {
-            Event::Deposit(tx) => tx.client,
-            Event::Withdrawal(tx) => tx.client,
-            Event::Dispute { client, .. } => client,
-            Event::Resolve { client, .. } => client,
-            Event::Chargeback { client, .. } => client,
+        if let Event::Query { client, reply } = &tx {
+            match self.tx_to_accounts.get(client) {
+                Some(sender) => sender.send(AccountMessage::Event(self.sequence, tx)).await?,
+                None => reply.send(self.purged.get(client).or(self.finished.get(client)).cloned()),
+            }
+            return Ok(());
+        }
+        self.config.count_received();
+        let client = match tx.client() {
+            Some(client) => client,
+            None => return self.sweep(tx).await,
         };
-        if let Some(sender) = self.tx_to_accounts.get(&client) {
-            sender.send(tx)?;
-        } else {
-            let (sender, receiver) = unbounded_channel();
-            let account = Account {
-                id: client,
-                available: dec!(0),
-                held: dec!(0),
-                is_locked: false,
-                incoming_tx: receiver,
-                transactions: self.transactions.clone(),
-            };
-            sender.send(tx)?;
-            self.tx_to_accounts.insert(client, sender);
-            let account = tokio::spawn(async move { account.process_txs().await });
-            self.result.push(account);
+        if self.purged.contains_key(&client) {
+            return Err(self.reject(tx, AccountingError::AccountPurged(client)));
+        }
+        if self.config.sorted_by_client {
+            if self.finished.contains_key(&client) {
+                return Err(self.reject(tx, AccountingError::ClientNotContiguous(client)));
+            }
+            self.finish_others(client).await;
+        }
+        if let Event::Transfer { to, tx_id, amount, .. } = tx {
+            return self.transfer(client, to, tx_id, amount, tx).await;
+        }
+        let tx = AccountMessage::Event(self.sequence, tx);
+        self.sequence += 1;
+        self.open(client).send(tx).await?;
+        Ok(())
+    }
+    /// Debits the transfer `tx` from the account of `from` once the account of `to` confirmed
+    /// that it can receive the funds, and credits them after that. Waits for both accounts,
+    /// so none of their other events get in between.
+    async fn transfer(
+        &mut self,
+        from: ClientId,
+        to: ClientId,
+        tx_id: TxId,
+        amount: Amount,
+        tx: Event,
+    ) -> Result<(), Box<dyn Error>> {
+        if to == from {
+            return Err(self.reject(tx, AccountingError::SelfTransfer(from)));
+        }
+        if self.purged.contains_key(&to) {
+            return Err(self.reject(tx, AccountingError::AccountPurged(to)));
+        }
+        // Another client's account would have to be open at the same time
+        if self.config.sorted_by_client {
+            return Err(self.reject(tx, AccountingError::ClientNotContiguous(to)));
+        }
+        let source = self.open(from);
+        // A new account can receive any amount, so it's only opened once the funds were debited
+        if let Some(target) = self.tx_to_accounts.get(&to).cloned() {
+            let (reply, credit) = oneshot::channel();
+            target.send(AccountMessage::CheckCredit(amount, reply)).await?;
+            if let Err(e) = credit.await? {
+                return Err(self.reject(tx, e));
+            }
+        }
+        let (reply, debited) = oneshot::channel();
+        source
+            .send(AccountMessage::Transfer(self.sequence, tx, reply))
+            .await?;
+        self.sequence += 1;
+        if debited.await? {
+            let target = self.open(to);
+            target.send(AccountMessage::Credit(tx_id, amount)).await?;
         }
         Ok(())
     }
+    /// The sender to the account of `client`, which is created if it doesn't exist yet
+    fn open(&mut self, client: ClientId) -> Sender<AccountMessage> {
+        if let Some(sender) = self.tx_to_accounts.get(&client) {
+            return sender.clone();
+        }
+        let (sender, receiver) = channel(self.config.channel_capacity());
+        let account = Account::new(
+            client,
+            receiver,
+            self.transactions.clone(),
+            self.config.clone(),
+            self.suspense_held.clone(),
+        );
+        account.emit(LifecycleEvent::AccountCreated(client));
+        if let Some(first_seen) = &self.config.first_seen {
+            first_seen.lock().unwrap().push(client);
+        }
+        self.spawn(account, sender.clone());
+        sender
+    }
+    /// Runs `account` in its own task, which receives the events sent to `sender`
+    fn spawn(&mut self, account: Account, sender: Sender<AccountMessage>) {
+        let client = account.id;
+        self.tx_to_accounts.insert(client, sender);
+        let purged = account.purged.clone();
+        let span = account.span();
+        let account = tokio::spawn(async move { account.process_txs().await }.instrument(span));
+        self.result.insert(client, (account, purged));
+    }
+    /// The state of the engine after the events that were already sent, which
+    /// [`AccountingEngine::from_snapshot`] continues from. The logs of
+    /// [`AccountingEngineBuilder::record_applied_events`] aren't part of it.
+    pub async fn snapshot(&mut self) -> EngineSnapshot {
+        self.route_pending().await;
+        let mut replies = vec![];
+        for (client, sender) in &self.tx_to_accounts {
+            let (reply, receiver) = oneshot::channel();
+            if sender.send(AccountMessage::Export(reply)).await.is_ok() {
+                replies.push((*client, receiver));
+            }
+        }
+        let mut accounts = BTreeMap::new();
+        for (client, receiver) in replies {
+            if let Ok(account) = receiver.await {
+                accounts.insert(client, account);
+            }
+        }
+        EngineSnapshot {
+            accounts,
+            transactions: self.transactions.read().await.values().cloned().collect(),
+            purged: self.purged.clone(),
+            finished: self.finished.clone(),
+            suspense_held: *self.suspense_held.lock().unwrap(),
+            sequence: self.sequence,
+            report: self.report.clone(),
+        }
+    }
+    /// An engine with the default configuration that continues from `snapshot`,
+    /// see [`AccountingEngineBuilder::restore`]
+    pub fn from_snapshot(snapshot: EngineSnapshot) -> (Self, Sender<Event>) {
+        Self::builder().restore(snapshot).build()
+    }
+}
+/// Configures an [`AccountingEngine`] one option at a time.
+/// [`AccountingEngineBuilder::build`] returns the engine and the sender for its events.
+#[derive(Default)]
+pub struct AccountingEngineBuilder {
+    config: EngineConfig,
+    snapshot: Option<EngineSnapshot>,
+}
+impl AccountingEngineBuilder {
+    /// Treat withdrawals of exactly zero as a no-op instead of rejecting them
+    pub fn allow_zero_withdrawals(mut self, allow: bool) -> Self {
+        self.config.allow_zero_withdrawals = allow;
+        self
+    }
+    /// Report every accepted event together with its position in the input stream
+    pub fn echo_events(mut self, echo: UnboundedSender<(u64, Event)>) -> Self {
+        self.config.echo = Some(echo);
+        self
+    }
+    /// Keep deposits pending until `delay` later events of the same client were handled
+    pub fn clearing_delay(mut self, delay: usize) -> Self {
+        self.config.clearing_delay = delay;
+        self
+    }
+    /// Reject operations that would make an account's held funds negative
+    /// with [`AccountingError::WouldGoNegative`]
+    pub fn reject_negative_held(mut self, reject: bool) -> Self {
+        self.config.reject_negative_held = reject;
+        self
+    }
+    /// Route disputed funds that would push an account's held above `cap`
+    /// into the held funds of `suspense_account`
+    pub fn held_cap(mut self, cap: Amount, suspense_account: ClientId) -> Self {
+        self.config.held_cap = Some(HeldCap {
+            cap,
+            suspense_account,
+        });
+        self
+    }
+    /// Reject every event of a client after its first `max` events with
+    /// [`AccountingError::ClientEventLimitExceeded`]. With `freeze` the account is frozen as well.
+    pub fn max_events_per_client(mut self, max: usize, freeze: bool) -> Self {
+        self.config.event_limit = Some(EventLimit { max, freeze });
+        self
+    }
+    /// Assume that the events of every client form one contiguous block, so only the account
+    /// of the current client is kept open. Events of a client after its block ended are
+    /// rejected with [`AccountingError::ClientNotContiguous`].
+    pub fn sorted_by_client(mut self, sorted: bool) -> Self {
+        self.config.sorted_by_client = sorted;
+        self
+    }
+    /// Let a dispute hold only the funds that are still available. The rest of the disputed
+    /// amount is recorded as a shortfall instead of driving the available funds negative.
+    pub fn dispute_clamp(mut self, clamp: bool) -> Self {
+        self.config.dispute_clamp = clamp;
+        self
+    }
+    /// Decide what chargebacks that leave the available funds negative do
+    pub fn chargeback_policy(mut self, policy: ChargebackPolicy) -> Self {
+        self.config.chargeback_policy = policy;
+        self
+    }
+    /// Round the balances of an account to `scale` decimal places after every operation
+    /// instead of only in the output. What rounding adds or removes counts towards
+    /// [`AccountingResult::external`].
+    pub fn round_each_operation(mut self, scale: u32, strategy: RoundingStrategy) -> Self {
+        self.config.rounding = Some(Rounding { scale, strategy });
+        self
+    }
+    /// Count the received and rejected events in `counters`
+    pub fn count_events(mut self, counters: Arc<EventCounters>) -> Self {
+        self.config.counters = Some(counters);
+        self
+    }
+    /// Count deposits, insufficient funds, disputes, chargebacks and frozen accounts in `metrics`
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+    /// Reject deposits and withdrawals of more than `limit`
+    pub fn max_amount(mut self, limit: Amount) -> Self {
+        self.config.max_amount = Some(limit);
+        self
+    }
+    /// Skip replayed deposits and withdrawals that are identical to the recorded ones with
+    /// their ids. Transactions that reuse an id with other fields are still rejected.
+    pub fn idempotent(mut self, idempotent: bool) -> Self {
+        self.config.idempotent = idempotent;
+        self
+    }
+    /// Accept deposits that arrive already disputed and hold their funds.
+    /// Without it they are rejected, since no csv input produces them.
+    pub fn locked_deposits(mut self, locked_deposits: bool) -> Self {
+        self.config.locked_deposits = locked_deposits;
+        self
+    }
+    /// Let withdrawals and other debits take the available funds down to `-limit`
+    pub fn overdraft_limit(mut self, limit: Amount) -> Self {
+        self.config.overdraft_limit = limit;
+        self
+    }
+    /// Let the channels to the engine and to every account queue at most `capacity` events.
+    /// Sending to a full channel waits until the receiver caught up.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.config.channel_capacity = Some(capacity);
+        self
+    }
+    /// Queue withdrawals that exceed the available funds and retry them in order after every
+    /// later deposit. Those still queued when the events end are rejected.
+    pub fn queue_withdrawals(mut self, queue: bool) -> Self {
+        self.config.queue_withdrawals = queue;
+        self
+    }
+    /// Keep a log of the events every account applied, see [`AccountingResult::log`]
+    pub fn record_applied_events(mut self, record: bool) -> Self {
+        self.config.applied_log = record;
+        self
+    }
+    /// Only check the events: the output has no results, just the events that would be rejected.
+    /// Rejections that depend on the balances, like insufficient funds, are left out.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+    /// Record the clients in `first_seen` in the order their first events arrived
+    pub fn record_first_seen(mut self, first_seen: Arc<Mutex<Vec<ClientId>>>) -> Self {
+        self.config.first_seen = Some(first_seen);
+        self
+    }
+    /// Call `callback` with the client of every account that a chargeback freezes
+    pub fn on_chargeback_freeze(
+        mut self,
+        callback: impl Fn(ClientId) + Send + Sync + 'static,
+    ) -> Self {
+        self.config.on_chargeback_freeze = Some(FreezeCallback(Arc::new(callback)));
+        self
+    }
+    /// Report account lifecycle events to `lifecycle` as they occur
+    pub fn lifecycle(mut self, lifecycle: UnboundedSender<LifecycleEvent>) -> Self {
+        self.config.lifecycle = Some(lifecycle);
+        self
+    }
+    /// Continue from the state of an engine taken by [`AccountingEngine::snapshot`]
+    pub fn restore(mut self, snapshot: EngineSnapshot) -> Self {
+        self.snapshot = Some(snapshot);
+        self
+    }
+    /// Builds the engine. With a snapshot to restore this must be called within a tokio
+    /// runtime, which runs the restored accounts.
+    pub fn build(self) -> (AccountingEngine, Sender<Event>) {
+        let (sender, receiver) = channel(self.config.channel_capacity());
+        let mut engine = AccountingEngine {
+            incoming_tx: receiver,
+            transactions: Arc::new(Default::default()),
+            tx_to_accounts: Default::default(),
+            result: Default::default(),
+            purged: Default::default(),
+            finished: Default::default(),
+            config: Arc::new(self.config),
+            suspense_held: Default::default(),
+            sequence: 0,
+            rejections: vec![],
+            report: Default::default(),
+            panicked: vec![],
+        };
+        if let Some(snapshot) = self.snapshot {
+            let transactions = snapshot.transactions.into_iter();
+            engine.transactions = Arc::new(RwLock::new(
+                transactions.map(|tx| ((tx.client, tx.id), tx)).collect(),
+            ));
+            engine.purged = snapshot.purged;
+            engine.finished = snapshot.finished;
+            engine.suspense_held = Arc::new(Mutex::new(snapshot.suspense_held));
+            engine.sequence = snapshot.sequence;
+            engine.report = snapshot.report;
+            for (client, account) in snapshot.accounts {
+                let (account_sender, account_receiver) = channel(engine.config.channel_capacity());
+                let account = Account::restore(
+                    client,
+                    account,
+                    account_receiver,
+                    engine.transactions.clone(),
+                    engine.config.clone(),
+                    engine.suspense_held.clone(),
+                );
+                engine.spawn(account, account_sender);
+            }
+        }
+        (engine, sender)
+    }
+    /// Applies `events` in order on the current thread, without the channels and tasks of
+    /// [`AccountingEngine::process_txs`] but with the same output.
+    /// Runs without a tokio runtime and panics if called from within one.
+    pub fn process_txs_sync(self, events: impl IntoIterator<Item = Event>) -> EngineOutput {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("failed to create a runtime for the accounts")
+            .block_on(sequential::process(self.config, events))
+    }
+    /// Builds the engine, sends it `events` and returns its output once all of them were handled
+    pub async fn process_events(
+        self,
+        events: impl IntoIterator<Item = Event>,
+    ) -> Result<EngineOutput, EngineError> {
+        let (engine, sender) = self.build();
+        let feed = async move {
+            for event in events {
+                if sender.send(event).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let ((), output) = tokio::join!(feed, engine.process_txs());
+        output
+    }
+}
+/// The state of an [`AccountingEngine`], see [`AccountingEngine::snapshot`]
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EngineSnapshot {
+    accounts: BTreeMap<ClientId, AccountSnapshot>,
+    transactions: Vec<Transaction>,
+    purged: BTreeMap<ClientId, AccountingResult>,
+    finished: BTreeMap<ClientId, AccountingResult>,
+    suspense_held: Amount,
+    sequence: u64,
+    report: ReconciliationReport,
+}
+/// What [`AccountingEngine::process_txs`] returns
+#[derive(Debug)]
+pub struct EngineOutput {
+    pub results: BTreeMap<ClientId, AccountingResult>,
+    /// Every rejected event with the reason, in the order of the input
+    pub rejections: Vec<(Event, AccountingError)>,
+    pub report: ReconciliationReport,
+}
+impl EngineOutput {
+    /// Orders the `rejections` by their positions in the input stream
+    /// and completes the `report` of the closed accounts with their `results`
+    pub(crate) fn new(
+        results: BTreeMap<ClientId, AccountingResult>,
+        mut rejections: Vec<(u64, Event, AccountingError)>,
+        mut report: ReconciliationReport,
+    ) -> Self {
+        rejections.sort_by_key(|(sequence, _, _)| *sequence);
+        report.held = results.values().map(|account| account.held).sum();
+        report.frozen_accounts = results.values().filter(|account| account.locked).count();
+        EngineOutput {
+            report,
+            results,
+            rejections: rejections
+                .into_iter()
+                .map(|(_, event, error)| (event, error))
+                .collect(),
+        }
+    }
+}
+/// Totals over all accounts at the end of the stream to reconcile a batch with
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ReconciliationReport {
+    /// Sum of the applied deposits
+    pub deposited: Amount,
+    /// Sum of the applied withdrawals
+    pub withdrawn: Amount,
+    /// Funds held over all accounts, including the suspense account
+    pub held: Amount,
+    pub frozen_accounts: usize,
+    /// Disputes that were neither resolved nor charged back
+    pub outstanding_disputes: usize,
+}
+impl ReconciliationReport {
+    pub(crate) fn add(&mut self, account: &Account) {
+        self.deposited += account.deposited;
+        self.withdrawn += account.withdrawn;
+        self.outstanding_disputes += account.disputes.len();
+    }
 }
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct AccountingResult {
     pub available: Amount,
     pub held: Amount,
+    /// Deposits that haven't cleared yet, see [`AccountingEngineBuilder::clearing_delay`]
+    pub pending: Amount,
     pub total: Amount,
     pub locked: bool,
+    /// The account was stopped by [`AccountingEngine::purge_client`]
+    pub purged: bool,
+    /// Net of the deposits, withdrawals, chargebacks, reversals and rounding of the account
+    pub external: Amount,
+    /// Transactions of the account that are under dispute
+    pub disputed_count: usize,
+    /// The events the account applied in order, if
+    /// [`AccountingEngineBuilder::record_applied_events`] is set. Rejected events are left out.
+    #[serde(skip)]
+    pub log: Vec<AppliedEvent>,
+}
+impl AccountingResult {
+    /// The total is made up of the available, held and pending funds
+    pub fn is_consistent(&self) -> bool {
+        self.funds() == self.total
+    }
+    /// The sum of the available, held and pending funds
+    pub fn funds(&self) -> Amount {
+        self.available + self.held + self.pending
+    }
+    /// Whether the amounts of both results differ by at most `epsilon` and everything else,
+    /// except for the logs, is equal
+    pub fn approx_eq(&self, other: &AccountingResult, epsilon: Amount) -> bool {
+        let amounts = [
+            (self.available, other.available),
+            (self.held, other.held),
+            (self.pending, other.pending),
+            (self.total, other.total),
+            (self.external, other.external),
+        ];
+        amounts.iter().all(|(a, b)| (a - b).abs() <= epsilon)
+            && (self.locked, self.purged, self.disputed_count)
+                == (other.locked, other.purged, other.disputed_count)
+    }
+}
+/// Processes `events` with the default configuration,
+/// see [`AccountingEngineBuilder::process_events`]
+pub async fn process_events(
+    events: impl IntoIterator<Item = Event>,
+) -> Result<EngineOutput, EngineError> {
+    AccountingEngine::builder().process_events(events).await
+}
+/// Asks the engine that receives the events of `sender` for the balance of `client`
+/// after the events that were sent before, without ending the stream.
+/// `None` if the client has no account or the engine stopped.
+pub async fn query_account(sender: &Sender<Event>, client: ClientId) -> Option<AccountingResult> {
+    let (query, answer) = Event::query(client);
+    sender.send(query).await.ok()?;
+    answer.await.ok()?
+}
+/// Adds the funds held by the suspense account of [`EngineConfig::held_cap`] to the results
+/// of all accounts and checks that no funds were created or lost
+pub(crate) fn finish_results(
+    config: &EngineConfig,
+    suspense_held: &Mutex<Amount>,
+    result: &mut BTreeMap<ClientId, AccountingResult>,
+) {
+    if config.dry_run {
+        result.clear();
+        return;
+    }
+    if let Some(held_cap) = &config.held_cap {
+        let excess = *suspense_held.lock().unwrap();
+        if excess != Amount::ZERO || result.contains_key(&held_cap.suspense_account) {
+            let suspense = result.entry(held_cap.suspense_account).or_default();
+            suspense.held += excess;
+            suspense.total = suspense.funds();
+        }
+    }
+    debug_assert_eq!(Amount::ZERO, conservation_discrepancy(result));
+}
+/// Panics with every client whose result isn't [`AccountingResult::is_consistent`]
+pub fn assert_consistent(result: &BTreeMap<ClientId, AccountingResult>) {
+    let inconsistent: Vec<_> = result
+        .iter()
+        .filter(|(_, account)| !account.is_consistent())
+        .map(|(client, account)| format!("client {}: {:?}", client, account))
+        .collect();
+    assert!(
+        inconsistent.is_empty(),
+        "available + held + pending != total for {}",
+        inconsistent.join(", ")
+    );
+}
+/// The difference between the sum of all totals and the net of the funds that entered or left
+/// the system. Money that only moves between accounts must not change it from zero.
+pub fn conservation_discrepancy(result: &BTreeMap<ClientId, AccountingResult>) -> Amount {
+    result
+        .values()
+        .map(|account| account.total - account.external)
+        .sum()
 }
 
 #[cfg(test)]
 #[allow(unused)]
 mod test {
+    use rust_decimal::{Decimal, RoundingStrategy};
     use rust_decimal_macros::dec;
-    use crate::engine::{AccountingEngine, AccountingResult};
-    use crate::transactions::{Event, Transaction};
+    use crate::engine::{
+        assert_consistent, conservation_discrepancy, process_events, query_account,
+        AccountingEngine, AccountingResult, ClientId, EngineOutput, ReconciliationReport, TxId,
+    };
+    use crate::config::ChargebackPolicy;
+    use crate::errors::{AccountingError, EngineError};
+    use crate::transactions::{Event, LifecycleEvent, Transaction, TxState};
+    use tokio::sync::mpsc::{unbounded_channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use tracing_test::traced_test;
 
     #[tokio::test]
     async fn one_client_deposits() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn two_clients_deposit() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 1,
-                client: 1,
-                amount: dec!(2),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::deposit(TxId(1), ClientId(1), dec!(2))).await.unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
         assert_eq!(
             &AccountingResult {
                 available: dec!(2),
-                held: dec!(0),
                 total: dec!(2),
-                locked: false
+                external: dec!(2),
+                ..Default::default()
             },
-            result.get(&1).unwrap()
+            result.get(&ClientId(1)).unwrap()
         );
     }
     #[tokio::test]
     async fn one_client_deposits_and_withdrawals() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
-        sender
-            .send(Event::Withdrawal(Transaction {
-                client: 0,
-                id: 1,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(1))).await.unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
-            &AccountingResult {
-                available: dec!(0),
-                held: dec!(0),
-                total: dec!(0),
-                locked: false
-            },
-            result.get(&0).unwrap()
+            &AccountingResult::default(),
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn two_clients_deposit_and_withdrawal() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
-        sender
-            .send(Event::Withdrawal(Transaction {
-                id: 1,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 2,
-                client: 1,
-                amount: dec!(2),
-                is_locked: false,
-            }))
-            .unwrap();
-        sender
-            .send(Event::Withdrawal(Transaction {
-                id: 3,
-                client: 1,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(1), dec!(2))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(3), ClientId(1), dec!(1))).await.unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
-            &AccountingResult {
-                available: dec!(0),
-                held: dec!(0),
-                total: dec!(0),
-                locked: false
-            },
-            result.get(&0).unwrap()
+            &AccountingResult::default(),
+            result.get(&ClientId(0)).unwrap()
         );
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&1).unwrap()
+            result.get(&ClientId(1)).unwrap()
         );
     }
     #[tokio::test]
     async fn one_client_charges_back() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
 
         sender
             .send(Event::Dispute {
-                client: 0,
-                tx_id: 0,
+                client: ClientId(0),
+                tx_id: TxId(0),
             })
+            .await
             .unwrap();
         sender
             .send(Event::Chargeback {
-                client: 0,
-                tx_id: 0,
+                client: ClientId(0),
+                tx_id: TxId(0),
             })
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
-                available: dec!(0),
-                held: dec!(0),
-                total: dec!(0),
                 locked: true,
+                ..Default::default()
+            },
+            result.get(&ClientId(0)).unwrap()
+        );
+    }
+    #[tokio::test]
+    async fn process_events_in_one_call() {
+        let output = process_events([
+            Event::deposit(TxId(0), ClientId(0), dec!(1)),
+            Event::Dispute { client: ClientId(0), tx_id: TxId(0) },
+            Event::Chargeback { client: ClientId(0), tx_id: TxId(0) },
+        ])
+        .await
+        .unwrap();
+        assert!(output.rejections.is_empty());
+        let result = &output.results[&ClientId(0)];
+        assert_eq!((dec!(0), dec!(0)), (result.total, result.held));
+        assert!(output.results[&ClientId(0)].locked);
+
+        let output = AccountingEngine::builder()
+            .channel_capacity(1)
+            .process_events((0..100).map(|id| Event::deposit(TxId(id), ClientId(0), dec!(1))))
+            .await
+            .unwrap();
+        assert_eq!(dec!(100), output.results[&ClientId(0)].available);
+    }
+    #[test]
+    fn reconciliation_report_of_a_batch() {
+        let events = || {
+            vec![
+                Event::deposit(TxId(0), ClientId(1), dec!(10)),
+                Event::withdrawal(TxId(1), ClientId(1), dec!(4)),
+                Event::withdrawal(TxId(2), ClientId(1), dec!(100)),
+                Event::deposit(TxId(3), ClientId(1), dec!(3)),
+                Event::Dispute { client: ClientId(1), tx_id: TxId(3) },
+                Event::deposit(TxId(0), ClientId(2), dec!(5)),
+                Event::Dispute { client: ClientId(2), tx_id: TxId(0) },
+                Event::Chargeback { client: ClientId(2), tx_id: TxId(0) },
+                Event::deposit(TxId(1), ClientId(2), dec!(1)),
+                Event::deposit(TxId(0), ClientId(3), dec!(2)),
+                Event::Dispute { client: ClientId(3), tx_id: TxId(0) },
+                Event::Resolve { client: ClientId(3), tx_id: TxId(0) },
+                Event::withdrawal(TxId(1), ClientId(3), dec!(1)),
+            ]
+        };
+        let expected = ReconciliationReport {
+            deposited: dec!(20),
+            withdrawn: dec!(5),
+            held: dec!(3),
+            frozen_accounts: 1,
+            outstanding_disputes: 1,
+        };
+        let output = AccountingEngine::builder().process_txs_sync(events());
+        assert_eq!(expected, output.report);
+        assert_eq!(2, output.rejections.len());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let output = runtime.block_on(process_events(events())).unwrap();
+        assert_eq!(expected, output.report);
+    }
+    #[tokio::test]
+    async fn panicking_event_is_rejected_and_rolled_back() {
+        let (engine, sender) = AccountingEngine::builder()
+            .on_chargeback_freeze(|client| assert_ne!(ClientId(1), client, "freezing fails"))
+            .build();
+        for client in [ClientId(0), ClientId(1)] {
+            sender.send(Event::deposit(TxId(0), client, dec!(1))).await.unwrap();
+            sender.send(Event::Dispute { client, tx_id: TxId(0) }).await.unwrap();
+            sender.send(Event::Chargeback { client, tx_id: TxId(0) }).await.unwrap();
+        }
+        // The chargeback didn't happen, so the transaction is still disputed
+        sender.send(Event::Resolve { client: ClientId(1), tx_id: TxId(0) }).await.unwrap();
+        sender.send(Event::deposit(TxId(1), ClientId(1), dec!(2))).await.unwrap();
+        drop(sender);
+        let output = engine.process_txs().await.unwrap();
+        let charged_back = &output.results[&ClientId(0)];
+        assert_eq!((dec!(0), true), (charged_back.total, charged_back.locked));
+        let panicked = &output.results[&ClientId(1)];
+        assert_eq!(
+            (dec!(3), dec!(0), false, 0),
+            (panicked.available, panicked.held, panicked.locked, panicked.disputed_count)
+        );
+        assert_eq!(1, output.rejections.len());
+        assert!(matches!(
+            &output.rejections[0],
+            (
+                Event::Chargeback { client: ClientId(1), .. },
+                AccountingError::Panicked { client: ClientId(1), message }
+            ) if message.contains("freezing fails")
+        ));
+    }
+    #[tokio::test]
+    async fn disputed_count_after_resolves() {
+        use crate::output::{convert_output, write_output, OutputOptions};
+        let dispute = |tx_id| Event::Dispute { client: ClientId(0), tx_id: TxId(tx_id) };
+        let output = process_events([
+            Event::deposit(TxId(0), ClientId(0), dec!(1)),
+            Event::deposit(TxId(1), ClientId(0), dec!(2)),
+            dispute(0),
+            dispute(1),
+            Event::Resolve {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            },
+        ])
+        .await
+        .unwrap();
+        assert_eq!(1, output.results[&ClientId(0)].disputed_count);
+
+        let output = process_events([
+            Event::deposit(TxId(0), ClientId(0), dec!(1)),
+            dispute(0),
+            Event::Resolve {
+                client: ClientId(0),
+                tx_id: TxId(0),
             },
-            result.get(&0).unwrap()
+        ])
+        .await
+        .unwrap();
+        assert_eq!(0, output.results[&ClientId(0)].disputed_count);
+        let options = OutputOptions {
+            disputed_count: true,
+            ..Default::default()
+        };
+        let mut written = vec![];
+        write_output(&mut written, convert_output(output.results, options)).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked,disputed_count\n0,1,0,1,false,0\n",
+            String::from_utf8(written).unwrap()
         );
     }
+    #[test]
+    fn results_within_epsilon_are_approximately_equal() {
+        let result = AccountingResult {
+            available: dec!(1) / dec!(3),
+            held: dec!(2) / dec!(3),
+            total: dec!(1),
+            external: dec!(1),
+            disputed_count: 1,
+            ..Default::default()
+        };
+        let rounded = AccountingResult {
+            available: dec!(0.3333),
+            held: dec!(0.6667),
+            ..result.clone()
+        };
+        assert_ne!(result, rounded);
+        assert!(result.approx_eq(&rounded, dec!(0.0001)));
+        assert!(rounded.approx_eq(&result, dec!(0.0001)));
+        assert!(!result.approx_eq(&rounded, dec!(0.00001)));
+        let locked = AccountingResult {
+            locked: true,
+            ..result.clone()
+        };
+        assert!(!result.approx_eq(&locked, dec!(1)));
+    }
     #[tokio::test]
     async fn one_client_resolves_dispute() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
 
         sender
             .send(Event::Dispute {
-                client: 0,
-                tx_id: 0,
+                client: ClientId(0),
+                tx_id: TxId(0),
             })
+            .await
             .unwrap();
         sender
             .send(Event::Resolve {
-                client: 0,
-                tx_id: 0,
+                client: ClientId(0),
+                tx_id: TxId(0),
             })
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false,
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn dispute_non_existent_tx() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
         sender
             .send(Event::Dispute {
-                client: 0,
-                tx_id: 1
+                client: ClientId(0),
+                tx_id: TxId(1)
             })
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn resolve_non_locket_tx() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
         sender
             .send(Event::Resolve {
-                client: 0,
-                tx_id: 0
+                client: ClientId(0),
+                tx_id: TxId(0)
             })
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn dispute_locked_tx() {
         let (engine, sender) = AccountingEngine::new();
-        sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
         sender
             .send(Event::Dispute {
-                client: 0,
-                tx_id: 0
+                client: ClientId(0),
+                tx_id: TxId(0)
             })
+            .await
             .unwrap();
         sender
             .send(Event::Dispute {
-                client: 0,
-                tx_id: 0
+                client: ClientId(0),
+                tx_id: TxId(0)
             })
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
-                available: dec!(0),
                 held: dec!(1),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                disputed_count: 1,
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn deposit_locked_tx() {
-        let (engine, sender) = AccountingEngine::new();
+        let (engine, sender) = AccountingEngine::builder().locked_deposits(true).build();
         sender
             .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
+                id: TxId(0),
+                client: ClientId(0),
                 amount: dec!(1),
-                is_locked: true,
+                state: TxState::Disputed,
+                line: None,
+                is_withdrawal: false,
+                disputed: None,
+                currency: None,
+                timestamp: None,
             }))
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
-                available: dec!(0),
                 held: dec!(1),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                disputed_count: 1,
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
-    async fn withdraw_more_than_deposited() {
-        let (engine, sender) = AccountingEngine::new();
+    async fn locked_deposit_is_rejected_by_default() {
+        let locked = Transaction {
+            state: TxState::Disputed,
+            ..Transaction::new(TxId(0), ClientId(0), dec!(1))
+        };
+        let output = process_events([Event::Deposit(locked)]).await.unwrap();
+        let result = &output.results[&ClientId(0)];
+        assert_eq!((dec!(0), dec!(0), 0), (result.total, result.held, result.disputed_count));
+        assert!(matches!(output.rejections[..], [(_, AccountingError::LockedDeposit(TxId(0)))]));
+    }
+    #[tokio::test]
+    async fn dispute_of_a_locked_deposit() {
+        let (engine, sender) = AccountingEngine::builder().locked_deposits(true).build();
+        let locked = Transaction {
+            state: TxState::Disputed,
+            ..Transaction::new(TxId(0), ClientId(0), dec!(1))
+        };
+        sender.send(Event::Deposit(locked)).await.unwrap();
         sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
+            .unwrap();
+        let partial = Event::PartialDispute {
+            client: ClientId(0),
+            tx_id: TxId(0),
+            amount: dec!(0.5),
+        };
+        sender.send(partial).await.unwrap();
+        drop(sender);
+        let output = engine.process_txs().await.unwrap();
+        assert_eq!(dec!(0), output.results[&ClientId(0)].available);
+        assert_eq!(dec!(1), output.results[&ClientId(0)].held);
+        assert_eq!(2, output.rejections.len());
+        for (_, error) in &output.rejections {
+            assert!(matches!(
+                error,
+                AccountingError::TransactionIsAlreadyLocked { tx_id: TxId(0), .. }
+            ));
+        }
+
+        let (engine, sender) = AccountingEngine::builder().locked_deposits(true).build();
+        let locked = Transaction {
+            state: TxState::Disputed,
+            ..Transaction::new(TxId(0), ClientId(0), dec!(1))
+        };
+        sender.send(Event::Deposit(locked)).await.unwrap();
+        sender
+            .send(Event::Resolve {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
         sender
-            .send(Event::Withdrawal(Transaction {
-                id: 1,
-                client: 0,
-                amount: dec!(2),
-                is_locked: false,
-            }))
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let output = engine.process_txs().await.unwrap();
+        assert_eq!(dec!(0), output.results[&ClientId(0)].available);
+        assert_eq!(dec!(1), output.results[&ClientId(0)].held);
+        assert!(output.rejections.is_empty());
+    }
+    #[tokio::test]
+    async fn withdraw_more_than_deposited() {
+        let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(2))).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
-                locked: false
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
     async fn charge_back_on_zero_balance() {
         let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(1))).await.unwrap();
         sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
-            .unwrap();
-        sender
-            .send(Event::Withdrawal(Transaction {
-                id: 1,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
-        sender.send(Event::Dispute { client: 0, tx_id: 0 }).unwrap();
-        sender.send(Event::Chargeback { client: 0, tx_id: 0 }).unwrap();
+        sender.send(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
-                //TODO Should a chargeback on zero balance result in negative balance?
+                // ChargebackPolicy::AllowNegative leaves the client owing the withdrawn funds
                 available: dec!(-1),
-                held: dec!(0),
                 total: dec!(-1),
-                locked: true
+                locked: true,
+                external: dec!(-1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&ClientId(0)).unwrap()
         );
     }
     #[tokio::test]
+    async fn chargeback_policies_on_zero_balance() {
+        async fn charge_back(policy: ChargebackPolicy) -> (AccountingResult, usize) {
+            let (engine, sender) = AccountingEngine::builder().chargeback_policy(policy).build();
+            sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+            sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(1))).await.unwrap();
+            sender
+                .send(Event::Dispute {
+                    client: ClientId(0),
+                    tx_id: TxId(0),
+                })
+                .await
+                .unwrap();
+            sender.send(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+            drop(sender);
+            let mut output = engine.process_txs().await.unwrap();
+            (output.results.remove(&ClientId(0)).unwrap(), output.rejections.len())
+        }
+        let (negative, rejected) = charge_back(ChargebackPolicy::AllowNegative).await;
+        assert_eq!((dec!(-1), dec!(0), true), (negative.available, negative.held, negative.locked));
+        assert_eq!(0, rejected);
+
+        let (clamped, rejected) = charge_back(ChargebackPolicy::ClampToZero).await;
+        assert_eq!((dec!(0), dec!(0), true), (clamped.available, clamped.held, clamped.locked));
+        assert_eq!(dec!(0), clamped.external);
+        assert_eq!(0, rejected);
+
+        let (kept, rejected) = charge_back(ChargebackPolicy::Reject).await;
+        assert_eq!((dec!(-1), dec!(1), false), (kept.available, kept.held, kept.locked));
+        assert_eq!(1, rejected);
+    }
+    #[tokio::test]
     async fn charge_back_a_withdrawal() {
+        let (mut engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        let disputed = engine.snapshot_many(&[ClientId(0)]).await;
+        let disputed = disputed.get(&ClientId(0)).unwrap();
+        assert_eq!((dec!(0), dec!(1)), (disputed.available, disputed.held));
+        sender.send(Event::Chargeback { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(
+            &AccountingResult {
+                available: dec!(1),
+                total: dec!(1),
+                locked: true,
+                external: dec!(1),
+                ..Default::default()
+            },
+            result.get(&ClientId(0)).unwrap()
+        );
+    }
+    #[tokio::test]
+    async fn resolve_a_disputed_withdrawal() {
+        let output = process_events([
+            Event::deposit(TxId(0), ClientId(0), dec!(3)),
+            Event::withdrawal(TxId(1), ClientId(0), dec!(1)),
+            Event::Dispute { client: ClientId(0), tx_id: TxId(1) },
+            Event::Resolve { client: ClientId(0), tx_id: TxId(1) },
+        ])
+        .await
+        .unwrap();
+        assert_eq!(
+            &AccountingResult {
+                available: dec!(2),
+                total: dec!(2),
+                external: dec!(2),
+                ..Default::default()
+            },
+            output.results.get(&ClientId(0)).unwrap()
+        );
+    }
+    #[tokio::test]
+    async fn deposits_and_dispute() {
         let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1.1))).await.unwrap();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(200.4567))).await.unwrap();
         sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
+            .send(Event::Dispute { client: ClientId(0), tx_id: TxId(0) })
+            .await
             .unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(
+            &AccountingResult {
+                available: dec!(200.4567),
+                held: dec!(1.1),
+                total: dec!(201.5567),
+                external: dec!(201.5567),
+                disputed_count: 1,
+                ..Default::default()
+            },
+            result.get(&ClientId(0)).unwrap()
+        );
+    }
+    #[tokio::test]
+    async fn chargeback_freeze_calls_back_once() {
+        let frozen = Arc::new(Mutex::new(vec![]));
+        let called = frozen.clone();
+        let events = vec![
+            Event::deposit(TxId(0), ClientId(1), dec!(1)),
+            Event::deposit(TxId(1), ClientId(1), dec!(1)),
+            Event::Dispute { client: ClientId(1), tx_id: TxId(0) },
+            Event::Dispute { client: ClientId(1), tx_id: TxId(1) },
+            Event::Chargeback { client: ClientId(1), tx_id: TxId(0) },
+            Event::Chargeback { client: ClientId(1), tx_id: TxId(1) },
+            Event::deposit(TxId(0), ClientId(2), dec!(1)),
+            Event::Dispute { client: ClientId(2), tx_id: TxId(0) },
+            Event::Resolve { client: ClientId(2), tx_id: TxId(0) },
+            Event::deposit(TxId(0), ClientId(3), dec!(1)),
+            Event::Dispute { client: ClientId(3), tx_id: TxId(0) },
+            Event::Freeze { client: ClientId(3) },
+            Event::Chargeback { client: ClientId(3), tx_id: TxId(0) },
+        ];
+        let output = AccountingEngine::builder()
+            .on_chargeback_freeze(move |client| called.lock().unwrap().push(client))
+            .process_events(events)
+            .await
+            .unwrap();
+        assert!(output.rejections.is_empty());
+        assert!(output.results[&ClientId(3)].locked);
+        assert_eq!(vec![ClientId(1)], *frozen.lock().unwrap());
+    }
+    #[tokio::test]
+    async fn lifecycle_of_charged_back_account() {
+        let (lifecycle, mut events) = unbounded_channel();
+        let (engine, sender) = AccountingEngine::builder().lifecycle(lifecycle).build();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(1))).await.unwrap();
         sender
-            .send(Event::Withdrawal(Transaction {
-                id: 1,
-                client: 0,
-                amount: dec!(1),
-                is_locked: false,
-            }))
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
+            .unwrap();
+        sender.send(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        drop(sender);
+        engine.process_txs().await.unwrap();
+        assert_eq!(Some(LifecycleEvent::AccountCreated(ClientId(0))), events.recv().await);
+        assert_eq!(Some(LifecycleEvent::AccountFrozen(ClientId(0))), events.recv().await);
+        assert_eq!(Some(LifecycleEvent::AccountClosed(ClientId(0))), events.recv().await);
+        assert_eq!(None, events.recv().await);
+    }
+    #[tokio::test]
+    async fn dispute_over_held_cap_overflows_into_suspense() {
+        let (engine, sender) = AccountingEngine::builder()
+            .held_cap(dec!(100), ClientId(999))
+            .build();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(150))).await.unwrap();
+        sender
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
-        sender.send(Event::Dispute { client: 0, tx_id: 1 }).unwrap();
-        sender.send(Event::Chargeback { client: 0, tx_id: 1 }).unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(
+            &AccountingResult {
+                held: dec!(100),
+                total: dec!(100),
+                external: dec!(150),
+                disputed_count: 1,
+                ..Default::default()
+            },
+            result.get(&ClientId(0)).unwrap()
+        );
+        assert_eq!(
+            &AccountingResult {
+                held: dec!(50),
+                total: dec!(50),
+                ..Default::default()
+            },
+            result.get(&ClientId(999)).unwrap()
+        );
+    }
+    #[tokio::test]
+    async fn resolve_releases_suspense() {
+        let (engine, sender) = AccountingEngine::builder()
+            .held_cap(dec!(100), ClientId(999))
+            .build();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(150))).await.unwrap();
+        sender
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
+            .unwrap();
+        sender
+            .send(Event::Resolve {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
+            .unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(dec!(150), result.get(&ClientId(0)).unwrap().available);
+        assert_eq!(dec!(0), result.get(&ClientId(0)).unwrap().held);
+        assert!(!result.contains_key(&ClientId(999)));
+    }
+    #[tokio::test]
+    async fn deposit_clears_after_delay() {
+        for (later_events, pending, available) in
+            [(0, dec!(10), dec!(0)), (1, dec!(10), dec!(0)), (2, dec!(0), dec!(10))]
+        {
+            let (engine, sender) = AccountingEngine::builder().clearing_delay(2).build();
+            sender.send(Event::deposit(TxId(0), ClientId(0), dec!(10))).await.unwrap();
+            for tx_id in 0..later_events {
+                sender
+                    .send(Event::Dispute {
+                        client: ClientId(0),
+                        tx_id: TxId(100 + tx_id),
+                    })
+                    .await
+                    .unwrap();
+            }
+            drop(sender);
+            let result = engine.process_txs().await.unwrap().results;
+            assert_eq!(
+                &AccountingResult {
+                    available,
+                    pending,
+                    total: dec!(10),
+                    external: dec!(10),
+                    ..Default::default()
+                },
+                result.get(&ClientId(0)).unwrap()
+            );
+        }
+    }
+    #[tokio::test]
+    async fn reverse_a_deposit() {
+        let (engine, sender) = AccountingEngine::new();
+        let transactions = engine.transactions.clone();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(3))).await.unwrap();
+        sender
+            .send(Event::Reverse {
+                client: ClientId(0),
+                tx_id: TxId(0),
+                new_tx_id: TxId(1),
+            })
+            .await
+            .unwrap();
+        sender
+            .send(Event::Reverse {
+                client: ClientId(0),
+                tx_id: TxId(0),
+                new_tx_id: TxId(2),
+            })
+            .await
+            .unwrap();
+        sender
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
+            .unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(
+            &AccountingResult::default(),
+            result.get(&ClientId(0)).unwrap()
+        );
+        let transactions = transactions.read().await;
+        assert_eq!(2, transactions.len());
+        assert_eq!(dec!(3), transactions.get(&(ClientId(0), TxId(0))).unwrap().signed_amount());
+        assert_eq!(dec!(-3), transactions.get(&(ClientId(0), TxId(1))).unwrap().signed_amount());
+    }
+    #[tokio::test]
+    async fn purge_discards_queued_events() {
+        let (mut engine, sender) = AccountingEngine::builder()
+            .channel_capacity(20_000)
+            .build();
+        let deposit = |id, client| {
+            Event::deposit(TxId(id), ClientId(client), dec!(1))
+        };
+        for id in 0..10_000 {
+            sender.send(deposit(id, 0)).await.unwrap();
+        }
+        sender.send(deposit(10_000, 1)).await.unwrap();
+        let purged = engine.purge_client(ClientId(0)).await.unwrap();
+        assert!(purged.purged);
+        assert!(purged.available <= dec!(10_000));
+        assert_eq!(purged.available, purged.total);
+        sender.send(deposit(10_001, 0)).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(&purged, result.get(&ClientId(0)).unwrap());
+        assert_eq!(dec!(1), result.get(&ClientId(1)).unwrap().available);
+        assert!(!result.get(&ClientId(1)).unwrap().purged);
+    }
+    #[tokio::test]
+    async fn events_beyond_client_limit_are_rejected() {
+        for freeze in [false, true] {
+            let (engine, sender) = AccountingEngine::builder()
+                .max_events_per_client(3, freeze)
+                .build();
+            for id in 0..4 {
+                sender.send(Event::deposit(TxId(id), ClientId(0), dec!(1))).await.unwrap();
+            }
+            for id in 4..7 {
+                sender.send(Event::deposit(TxId(id), ClientId(1), dec!(1))).await.unwrap();
+            }
+            drop(sender);
+            let result = engine.process_txs().await.unwrap().results;
+            let client = result.get(&ClientId(0)).unwrap();
+            assert_eq!((dec!(3), freeze), (client.available, client.locked));
+            let client = result.get(&ClientId(1)).unwrap();
+            assert_eq!((dec!(3), false), (client.available, client.locked));
+        }
+    }
+    #[tokio::test]
+    async fn conservation_holds_when_funds_move_to_suspense() {
+        let (engine, sender) = AccountingEngine::builder()
+            .held_cap(dec!(10), ClientId(999))
+            .build();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(30))).await.unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(1), dec!(25))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(3), ClientId(1), dec!(5))).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(1), tx_id: TxId(2) }).await.unwrap();
+        sender.send(Event::Chargeback { client: ClientId(1), tx_id: TxId(2) }).await.unwrap();
+        sender.send(Event::deposit(TxId(4), ClientId(2), dec!(3))).await.unwrap();
+        sender
+            .send(Event::Reverse {
+                client: ClientId(2),
+                tx_id: TxId(4),
+                new_tx_id: TxId(5),
+            })
+            .await
+            .unwrap();
+        drop(sender);
+        let mut result = engine.process_txs().await.unwrap().results;
+        assert_eq!(dec!(20), result.get(&ClientId(999)).unwrap().total);
+        assert_eq!(dec!(0), conservation_discrepancy(&result));
+
+        result.get_mut(&ClientId(0)).unwrap().total += dec!(1);
+        assert_eq!(dec!(1), conservation_discrepancy(&result));
+    }
+    #[tokio::test]
+    async fn sorted_by_client_closes_finished_accounts() {
+        let (lifecycle, mut events) = unbounded_channel();
+        let (engine, sender) = AccountingEngine::builder()
+            .sorted_by_client(true)
+            .lifecycle(lifecycle)
+            .build();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(2))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(2), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::deposit(TxId(3), ClientId(1), dec!(5))).await.unwrap();
+        sender.send(Event::deposit(TxId(4), ClientId(0), dec!(7))).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(dec!(1), result.get(&ClientId(0)).unwrap().available);
+        assert_eq!(dec!(5), result.get(&ClientId(1)).unwrap().available);
+        assert_eq!(Some(LifecycleEvent::AccountCreated(ClientId(0))), events.recv().await);
+        assert_eq!(Some(LifecycleEvent::AccountClosed(ClientId(0))), events.recv().await);
+        assert_eq!(Some(LifecycleEvent::AccountCreated(ClientId(1))), events.recv().await);
+        assert_eq!(Some(LifecycleEvent::AccountClosed(ClientId(1))), events.recv().await);
+    }
+    #[tokio::test]
+    async fn transaction_count_follows_the_store() {
+        let (echo, mut echoed) = unbounded_channel();
+        let (mut engine, sender) = AccountingEngine::builder().echo_events(echo).build();
+        assert_eq!(0, engine.transaction_count().await);
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(0), dec!(1))).await.unwrap();
+        sender.send(Event::deposit(TxId(3), ClientId(1), dec!(1))).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(1), tx_id: TxId(3) }).await.unwrap();
+        sender.send(Event::Chargeback { client: ClientId(1), tx_id: TxId(3) }).await.unwrap();
+        engine.route_pending().await;
+        for _ in 0..5 {
+            echoed.recv().await.unwrap();
+        }
+        assert_eq!(3, engine.transaction_count().await);
+
+        engine.purge_client(ClientId(0)).await.unwrap();
+        assert_eq!(1, engine.transaction_count().await);
+        sender.send(Event::deposit(TxId(4), ClientId(2), dec!(1))).await.unwrap();
+        engine.route_pending().await;
+        echoed.recv().await.unwrap();
+        assert_eq!(2, engine.transaction_count().await);
+    }
+    #[tokio::test]
+    async fn snapshot_of_some_clients() {
+        let (mut engine, sender) = AccountingEngine::new();
+        for client in 0..5 {
+            let id = client as u32;
+            let (client, amount) = (ClientId(client), Decimal::from(client));
+            sender.send(Event::deposit(TxId(id), client, dec!(10))).await.unwrap();
+            sender.send(Event::withdrawal(TxId(id + 10), client, amount)).await.unwrap();
+        }
+        let snapshot = engine.snapshot_many(&[ClientId(1), ClientId(3), ClientId(4)]).await;
+        assert_eq!(vec![1, 3, 4], snapshot.keys().map(|client| client.0).collect::<Vec<_>>());
+        assert_eq!(dec!(9), snapshot.get(&ClientId(1)).unwrap().available);
+        assert_eq!(dec!(7), snapshot.get(&ClientId(3)).unwrap().total);
+        assert_eq!(dec!(6), snapshot.get(&ClientId(4)).unwrap().available);
+
+        drop(sender);
+        assert_eq!(5, engine.process_txs().await.unwrap().results.len());
+    }
+    #[tokio::test]
+    async fn rounding_each_operation() {
+        async fn deposits(sender: Sender<Event>) {
+            for id in 0..3 {
+                sender.send(Event::deposit(TxId(id), ClientId(0), dec!(0.005))).await.unwrap();
+            }
+        }
+        let (engine, sender) = AccountingEngine::builder()
+            .round_each_operation(2, RoundingStrategy::MidpointAwayFromZero)
+            .build();
+        deposits(sender).await;
+        let result = engine.process_txs().await.unwrap().results;
+        let rounded = &result[&ClientId(0)];
+        assert_eq!((dec!(0.03), dec!(0.03)), (rounded.available, rounded.external));
+        assert_eq!(dec!(0), conservation_discrepancy(&result));
+
+        let (engine, sender) = AccountingEngine::new();
+        deposits(sender).await;
+        let result = engine.process_txs().await.unwrap().results;
+        let available = result.get(&ClientId(0)).unwrap().available;
+        assert_eq!(
+            dec!(0.02),
+            available.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)
+        );
+    }
+    #[tokio::test]
+    async fn fee_sweep_debits_every_account() {
+        let (engine, sender) = AccountingEngine::new();
+        for client in 0..3 {
+            let deposit = Event::deposit(TxId(client as u32), ClientId(client), dec!(10));
+            sender.send(deposit).await.unwrap();
+        }
+        sender.send(Event::FeeSweep { amount: dec!(1.5) }).await.unwrap();
+        sender.send(Event::deposit(TxId(3), ClientId(3), dec!(10))).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        for client in 0..3 {
+            assert_eq!(dec!(8.5), result.get(&ClientId(client)).unwrap().available);
+        }
+        assert_eq!(dec!(10), result.get(&ClientId(3)).unwrap().available);
+    }
+    #[tokio::test]
+    async fn engine_can_be_inspected_after_finalize() {
+        let (mut engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(2))).await.unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(1), dec!(3))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(3), ClientId(1), dec!(1))).await.unwrap();
+        let result = engine.finalize().await.unwrap();
+        assert_eq!(dec!(2), result.get(&ClientId(0)).unwrap().available);
+        assert_eq!(dec!(2), result.get(&ClientId(1)).unwrap().available);
+        assert_eq!(3, engine.transaction_count().await);
+        assert_eq!(result, engine.finalize().await.unwrap());
+    }
+    #[tokio::test]
+    async fn largest_client_and_transaction_ids() {
+        let (client, tx_id) = (ClientId(u16::MAX), TxId(u32::MAX));
+        let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(tx_id, client, dec!(3))).await.unwrap();
+        sender.send(Event::deposit(TxId(tx_id.0 - 1), client, dec!(1))).await.unwrap();
+        sender.send(Event::Dispute { client, tx_id }).await.unwrap();
+        sender.send(Event::Resolve { client, tx_id }).await.unwrap();
+        sender.send(Event::Dispute { client, tx_id }).await.unwrap();
+        sender.send(Event::Chargeback { client, tx_id }).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
         assert_eq!(
             &AccountingResult {
                 available: dec!(1),
-                held: dec!(0),
                 total: dec!(1),
                 locked: true,
+                external: dec!(1),
+                ..Default::default()
             },
-            result.get(&0).unwrap()
+            result.get(&client).unwrap()
         );
     }
     #[tokio::test]
-    async fn deposits_and_dispute() {
+    async fn full_channel_blocks_the_producer() {
+        let (engine, sender) = AccountingEngine::builder().channel_capacity(2).build();
+        for id in 0..2 {
+            sender.send(Event::deposit(TxId(id), ClientId(0), dec!(1))).await.unwrap();
+        }
+        let blocked = tokio::time::timeout(
+            std::time::Duration::from_millis(50),
+            sender.send(Event::deposit(TxId(2), ClientId(0), dec!(1))),
+        );
+        assert!(blocked.await.is_err());
+        assert_eq!(0, sender.capacity());
+
+        let processing = tokio::spawn(engine.process_txs());
+        for id in 2..100 {
+            sender.send(Event::deposit(TxId(id), ClientId(0), dec!(1))).await.unwrap();
+        }
+        drop(sender);
+        let result = processing.await.unwrap().unwrap().results;
+        assert_eq!(dec!(100), result.get(&ClientId(0)).unwrap().available);
+    }
+    #[tokio::test]
+    async fn first_seen_order_is_recorded() {
+        let first_seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let (engine, sender) = AccountingEngine::builder()
+            .record_first_seen(first_seen.clone())
+            .build();
+        for (id, client) in [(0, 3), (1, 1), (2, 3), (3, 2), (4, 1)] {
+            sender.send(Event::deposit(TxId(id), ClientId(client), dec!(1))).await.unwrap();
+        }
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert_eq!(vec![1, 2, 3], result.keys().map(|client| client.0).collect::<Vec<_>>());
+        assert_eq!(vec![ClientId(3), ClientId(1), ClientId(2)], *first_seen.lock().unwrap());
+    }
+    #[tokio::test]
+    async fn dispute_of_other_clients_transaction_changes_nothing() {
         let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        sender.send(Event::deposit(TxId(1), ClientId(1), dec!(7))).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        sender.send(Event::Chargeback { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        let balances = |client| {
+            let account: &AccountingResult = result.get(&client).unwrap();
+            (account.available, account.held, account.locked)
+        };
+        assert_eq!((dec!(5), dec!(0), false), balances(ClientId(0)));
+        assert_eq!((dec!(7), dec!(0), false), balances(ClientId(1)));
+    }
+    #[tokio::test]
+    async fn charged_back_transaction_is_kept_but_not_disputed_again() {
+        let counters = std::sync::Arc::new(crate::config::EventCounters::default());
+        let (mut engine, sender) = AccountingEngine::builder()
+            .count_events(counters.clone())
+            .build();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(2))).await.unwrap();
         sender
-            .send(Event::Deposit(Transaction {
-                id: 0,
-                client: 0,
-                amount: dec!(1.1),
-                is_locked: false,
-            }))
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
+        sender.send(Event::Chargeback { client: ClientId(0), tx_id: TxId(0) }).await.unwrap();
+        let charged_back = engine.snapshot_many(&[ClientId(0)]).await;
         sender
-            .send(Event::Deposit(Transaction {
-                id: 1,
-                client: 0,
-                amount: dec!(200.4567),
-                is_locked: false,
-            }))
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
+        let result = engine.finalize().await.unwrap();
+        assert_eq!(charged_back.get(&ClientId(0)), result.get(&ClientId(0)));
+        assert_eq!(dec!(2), result.get(&ClientId(0)).unwrap().available);
+        assert_eq!(2, engine.transaction_count().await);
+        let rejected = counters.rejected.load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(1, rejected);
+    }
+    #[tokio::test]
+    async fn results_are_consistent() {
+        let (engine, sender) = AccountingEngine::builder().clearing_delay(2).build();
+        sender.send(Event::deposit(TxId(0), ClientId(0), dec!(5))).await.unwrap();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(3))).await.unwrap();
         sender
-            .send(Event::Dispute { client: 0, tx_id: 0 })
+            .send(Event::Dispute {
+                client: ClientId(0),
+                tx_id: TxId(0),
+            })
+            .await
             .unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(1), dec!(4))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(3), ClientId(1), dec!(1))).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        assert!(result.values().all(AccountingResult::is_consistent));
+        assert_consistent(&result);
+    }
+    #[test]
+    #[should_panic(expected = "client 1")]
+    fn inconsistent_result_is_flagged() {
+        let consistent = AccountingResult {
+            available: dec!(1),
+            held: dec!(2),
+            total: dec!(3),
+            external: dec!(3),
+            ..Default::default()
+        };
+        let corrupted = AccountingResult {
+            held: dec!(1),
+            ..consistent.clone()
+        };
+        assert!(!corrupted.is_consistent());
+        assert_consistent(&[(ClientId(0), consistent), (ClientId(1), corrupted)].into());
+    }
+    #[tokio::test]
+    async fn withdrawal_before_deposit_is_queued() {
+        let (engine, sender) = AccountingEngine::builder().queue_withdrawals(true).build();
+        sender.send(Event::withdrawal(TxId(0), ClientId(0), dec!(3))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(1), ClientId(0), dec!(50))).await.unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(0), dec!(10))).await.unwrap();
+        drop(sender);
+        let result = engine.process_txs().await.unwrap().results;
+        let account = result.get(&ClientId(0)).unwrap();
+        assert_eq!((dec!(7), dec!(7)), (account.available, account.external));
+    }
+    #[tokio::test]
+    async fn log_of_a_resolved_dispute() {
+        let (engine, sender) = AccountingEngine::builder()
+            .record_applied_events(true)
+            .build();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(5))).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
+        sender.send(Event::Resolve { client: ClientId(0), tx_id: TxId(1) }).await.unwrap();
         drop(sender);
-        let result = engine.process_txs().await;
+        let result = engine.process_txs().await.unwrap().results;
+        let log: Vec<_> = result[&ClientId(0)]
+            .log
+            .iter()
+            .map(|applied| (applied.tx_id, applied.kind, applied.available, applied.held))
+            .collect();
         assert_eq!(
-            &AccountingResult {
-                available: dec!(200.4567),
-                held: dec!(1.1),
-                total: dec!(201.5567),
-                locked: false
+            vec![
+                (Some(TxId(1)), "deposit", dec!(5), dec!(0)),
+                (Some(TxId(1)), "dispute", dec!(0), dec!(5)),
+                (Some(TxId(1)), "resolve", dec!(5), dec!(0)),
+            ],
+            log
+        );
+    }
+    #[test]
+    fn sync_engine_matches_async_engine() {
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(0), dec!(10)),
+            Event::deposit(TxId(2), ClientId(1), dec!(4)),
+            Event::withdrawal(TxId(3), ClientId(0), dec!(3)),
+            Event::withdrawal(TxId(4), ClientId(1), dec!(5)),
+            Event::deposit(TxId(1), ClientId(1), dec!(1)),
+            Event::deposit(TxId(2), ClientId(1), dec!(1)),
+            Event::FeeSweep { amount: dec!(0.5) },
+            Event::Dispute { client: ClientId(0), tx_id: TxId(1) },
+            Event::PartialDispute {
+                client: ClientId(1),
+                tx_id: TxId(2),
+                amount: dec!(2),
+            },
+            Event::Resolve { client: ClientId(1), tx_id: TxId(2) },
+            Event::Chargeback { client: ClientId(0), tx_id: TxId(1) },
+            Event::deposit(TxId(5), ClientId(0), dec!(1)),
+            Event::Reverse {
+                client: ClientId(1),
+                tx_id: TxId(1),
+                new_tx_id: TxId(6),
             },
-            result.get(&0).unwrap()
+            Event::Dispute { client: ClientId(2), tx_id: TxId(9) },
+        ];
+        let debug = |output: EngineOutput| {
+            let rejections: Vec<_> = output
+                .rejections
+                .iter()
+                .map(|rejection| format!("{:?}", rejection))
+                .collect();
+            (output.results, rejections)
+        };
+        let sync = debug(AccountingEngine::builder().process_txs_sync(events.clone()));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let concurrent = debug(runtime.block_on(async {
+            let (engine, sender) = AccountingEngine::new();
+            for event in events {
+                sender.send(event).await.unwrap();
+            }
+            drop(sender);
+            engine.process_txs().await.unwrap()
+        }));
+        assert_eq!(concurrent, sync);
+        assert_eq!(4, sync.1.len());
+        assert_eq!(dec!(-3.5), sync.0[&ClientId(0)].available);
+    }
+    #[test]
+    fn transfer_between_clients() {
+        let transfer = |from, to, tx_id, amount| Event::Transfer {
+            from: ClientId(from),
+            to: ClientId(to),
+            tx_id: TxId(tx_id),
+            amount,
+        };
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(1), dec!(10)),
+            Event::deposit(TxId(2), ClientId(2), dec!(1)),
+            transfer(1, 2, 3, dec!(4)),
+            transfer(2, 1, 4, dec!(50)),
+            Event::Freeze { client: ClientId(3) },
+            transfer(1, 3, 5, dec!(1)),
+            transfer(1, 4, 6, dec!(2)),
+            transfer(2, 5, 7, dec!(50)),
+        ];
+        let sync = AccountingEngine::builder().process_txs_sync(events.clone());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let output = runtime.block_on(async {
+            let (engine, sender) = AccountingEngine::new();
+            for event in events {
+                sender.send(event).await.unwrap();
+            }
+            drop(sender);
+            engine.process_txs().await.unwrap()
+        });
+        assert_eq!(sync.results, output.results);
+        let result = output.results;
+        assert_eq!(dec!(4), result[&ClientId(1)].available);
+        assert_eq!(dec!(5), result[&ClientId(2)].available);
+        assert_eq!(dec!(0), result[&ClientId(3)].available);
+        assert_eq!(dec!(2), result[&ClientId(4)].available);
+        // The target of a rejected transfer doesn't get an account
+        assert!(!result.contains_key(&ClientId(5)));
+        assert_eq!(dec!(0), conservation_discrepancy(&result));
+        assert_eq!(3, output.rejections.len());
+        assert!(matches!(
+            &output.rejections[0],
+            (
+                Event::Transfer { tx_id: TxId(4), .. },
+                AccountingError::InsufficientFunds(ClientId(2))
+            )
+        ));
+        assert!(matches!(
+            &output.rejections[1],
+            (Event::Transfer { tx_id: TxId(5), .. }, AccountingError::AccountFrozen(ClientId(3)))
+        ));
+        assert_eq!(3, sync.rejections.len());
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resolve_and_chargeback_of_the_same_dispute() {
+        let (client, tx_id) = (ClientId(1), TxId(1));
+        let settlements = [Event::Resolve { client, tx_id }, Event::Chargeback { client, tx_id }];
+        for first in [0, 1] {
+            for _ in 0..20 {
+                let mut events = vec![];
+                for other in 2..10 {
+                    events.push(Event::deposit(tx_id, ClientId(other), dec!(1)));
+                }
+                events.push(Event::deposit(tx_id, client, dec!(5)));
+                events.push(Event::Dispute { client, tx_id });
+                events.push(settlements[first].clone());
+                events.push(settlements[1 - first].clone());
+                let output = process_events(events).await.unwrap();
+                let result = &output.results[&client];
+                let charged_back = first == 1;
+                assert_eq!(
+                    (dec!(0), charged_back, 0),
+                    (result.held, result.locked, result.disputed_count)
+                );
+                assert_eq!(if charged_back { dec!(0) } else { dec!(5) }, result.total);
+                // The second settlement finds the dispute settled already
+                match &output.rejections[..] {
+                    [(Event::Chargeback { .. }, error)] => assert!(matches!(
+                        error,
+                        AccountingError::TransactionIsNotDisputed { tx_id: TxId(1), .. }
+                    )),
+                    [(Event::Resolve { .. }, error)] => assert!(matches!(
+                        error,
+                        AccountingError::TransactionAlreadyChargedBack(TxId(1))
+                    )),
+                    rejections => panic!("unexpected rejections {:?}", rejections),
+                }
+            }
+        }
+    }
+    #[test]
+    fn self_transfer_is_rejected() {
+        let transfer = |tx_id| Event::Transfer {
+            from: ClientId(1),
+            to: ClientId(1),
+            tx_id: TxId(tx_id),
+            amount: dec!(2),
+        };
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(1), dec!(5)),
+            transfer(2),
+            Event::Freeze { client: ClientId(1) },
+            transfer(3),
+        ];
+        let sync = AccountingEngine::builder().process_txs_sync(events.clone());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let output = runtime.block_on(process_events(events)).unwrap();
+        assert_eq!(sync.results, output.results);
+        let result = &output.results[&ClientId(1)];
+        assert_eq!(
+            (dec!(5), dec!(5), true),
+            (result.available, result.external, result.locked)
         );
+        for rejections in [&output.rejections, &sync.rejections] {
+            assert!(matches!(
+                rejections[..],
+                [
+                    (Event::Transfer { .. }, AccountingError::SelfTransfer(ClientId(1))),
+                    (Event::Transfer { .. }, AccountingError::SelfTransfer(ClientId(1)))
+                ]
+            ));
+        }
+    }
+    #[tokio::test]
+    async fn restored_snapshot_continues_the_stream() {
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(0), dec!(10)),
+            Event::deposit(TxId(2), ClientId(1), dec!(5)),
+            Event::Dispute { client: ClientId(0), tx_id: TxId(1) },
+            Event::withdrawal(TxId(3), ClientId(1), dec!(2)),
+            Event::Dispute { client: ClientId(1), tx_id: TxId(2) },
+            Event::Chargeback { client: ClientId(1), tx_id: TxId(2) },
+            Event::Resolve { client: ClientId(0), tx_id: TxId(1) },
+            Event::withdrawal(TxId(4), ClientId(0), dec!(4)),
+            Event::deposit(TxId(5), ClientId(1), dec!(1)),
+            Event::deposit(TxId(3), ClientId(2), dec!(7)),
+            Event::deposit(TxId(1), ClientId(0), dec!(1)),
+        ];
+        let (engine, sender) = AccountingEngine::new();
+        for event in events.clone() {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+        let single_pass = engine.process_txs().await.unwrap().results;
+
+        let (mut engine, sender) = AccountingEngine::new();
+        for event in events[..4].iter().cloned() {
+            sender.send(event).await.unwrap();
+        }
+        let snapshot = serde_json::to_string(&engine.snapshot().await).unwrap();
+        drop(engine);
+        let snapshot = serde_json::from_str(&snapshot).unwrap();
+        let (engine, sender) = AccountingEngine::from_snapshot(snapshot);
+        for event in events[4..].iter().cloned() {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+        let output = engine.process_txs().await.unwrap();
+        assert_eq!(single_pass, output.results);
+        // The duplicate of tx 1 is only rejected if the restored engine knows the transaction
+        assert_eq!(2, output.rejections.len());
+    }
+    #[tokio::test]
+    async fn query_account_while_processing() {
+        let (engine, sender) = AccountingEngine::new();
+        let processing = tokio::spawn(engine.process_txs());
+        sender.send(Event::deposit(TxId(1), ClientId(42), dec!(3))).await.unwrap();
+        sender.send(Event::deposit(TxId(2), ClientId(42), dec!(4))).await.unwrap();
+        let balance = query_account(&sender, ClientId(42)).await.unwrap();
+        assert_eq!((dec!(7), dec!(7)), (balance.available, balance.total));
+        assert_eq!(None, query_account(&sender, ClientId(7)).await);
+        sender.send(Event::withdrawal(TxId(3), ClientId(42), dec!(5))).await.unwrap();
+        assert_eq!(dec!(2), query_account(&sender, ClientId(42)).await.unwrap().available);
+        drop(sender);
+        let output = processing.await.unwrap().unwrap();
+        assert_eq!(vec![&ClientId(42)], output.results.keys().collect::<Vec<_>>());
+        assert_eq!(dec!(2), output.results[&ClientId(42)].available);
+    }
+    #[tokio::test]
+    async fn tx_ids_are_per_client() {
+        let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(5), ClientId(0), dec!(2))).await.unwrap();
+        sender.send(Event::deposit(TxId(5), ClientId(1), dec!(3))).await.unwrap();
+        sender.send(Event::Dispute { client: ClientId(1), tx_id: TxId(5) }).await.unwrap();
+        drop(sender);
+        let output = engine.process_txs().await.unwrap();
+        assert!(output.rejections.is_empty());
+        let (first, second) = (&output.results[&ClientId(0)], &output.results[&ClientId(1)]);
+        assert_eq!((dec!(2), dec!(0)), (first.available, first.held));
+        assert_eq!((dec!(0), dec!(3)), (second.available, second.held));
+    }
+    #[tokio::test]
+    async fn rejections_are_returned_with_their_reasons() {
+        let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(1), ClientId(0), dec!(5))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(2), ClientId(0), dec!(6))).await.unwrap();
+        sender.send(Event::deposit(TxId(3), ClientId(1), dec!(1))).await.unwrap();
+        sender.send(Event::deposit(TxId(3), ClientId(1), dec!(1))).await.unwrap();
+        drop(sender);
+        let output = engine.process_txs().await.unwrap();
+        assert_eq!(dec!(5), output.results[&ClientId(0)].available);
+        assert_eq!(dec!(1), output.results[&ClientId(1)].available);
+        assert_eq!(2, output.rejections.len());
+        assert!(matches!(
+            &output.rejections[0],
+            (Event::Withdrawal(tx), AccountingError::InsufficientFunds(ClientId(0)))
+                if tx.id == TxId(2)
+        ));
+        assert!(matches!(
+            &output.rejections[1],
+            (Event::Deposit(tx), AccountingError::TransactionAlreadyExists(TxId(3)))
+                if tx.client == ClientId(1)
+        ));
+    }
+    #[tokio::test]
+    #[traced_test]
+    async fn over_withdrawal_logs_a_warning() {
+        let (engine, sender) = AccountingEngine::new();
+        sender.send(Event::deposit(TxId(1), ClientId(917), dec!(1))).await.unwrap();
+        sender.send(Event::withdrawal(TxId(2), ClientId(917), dec!(2))).await.unwrap();
+        drop(sender);
+        engine.process_txs().await.unwrap();
+        assert!(logs_contain("WARN"));
+        assert!(logs_contain("account{client=917}"));
+        assert!(logs_contain(
+            "rejected withdrawal client=Some(917) tx=Some(2) error=InsufficientFunds(917)"
+        ));
+    }
+    #[tokio::test]
+    async fn dry_run_reports_the_same_rejections() {
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(1), dec!(10)),
+            Event::deposit(TxId(1), ClientId(1), dec!(5)),
+            Event::Dispute {
+                client: ClientId(1),
+                tx_id: TxId(99),
+            },
+            Event::Resolve {
+                client: ClientId(1),
+                tx_id: TxId(1),
+            },
+            Event::Dispute {
+                client: ClientId(1),
+                tx_id: TxId(1),
+            },
+            Event::Dispute {
+                client: ClientId(1),
+                tx_id: TxId(1),
+            },
+            Event::Chargeback {
+                client: ClientId(1),
+                tx_id: TxId(1),
+            },
+            Event::deposit(TxId(2), ClientId(1), dec!(1)),
+            Event::withdrawal(TxId(3), ClientId(2), dec!(0)),
+            Event::deposit(TxId(4), ClientId(2), dec!(3)),
+            Event::Reverse {
+                client: ClientId(2),
+                tx_id: TxId(4),
+                new_tx_id: TxId(4),
+            },
+            Event::Reverse {
+                client: ClientId(2),
+                tx_id: TxId(4),
+                new_tx_id: TxId(5),
+            },
+            Event::Dispute {
+                client: ClientId(2),
+                tx_id: TxId(4),
+            },
+            Event::Chargeback {
+                client: ClientId(2),
+                tx_id: TxId(7),
+            },
+        ];
+        let run = |dry_run| {
+            let events = events.clone();
+            async move {
+                let (engine, sender) = AccountingEngine::builder().dry_run(dry_run).build();
+                for event in events {
+                    sender.send(event).await.unwrap();
+                }
+                drop(sender);
+                engine.process_txs().await.unwrap()
+            }
+        };
+        let checked = run(true).await;
+        let applied = run(false).await;
+        assert!(checked.results.is_empty());
+        assert_eq!(2, applied.results.len());
+        let reasons = |output: &EngineOutput| {
+            output
+                .rejections
+                .iter()
+                .map(|(event, error)| (event.clone(), format!("{:?}", error)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(9, reasons(&applied).len());
+        assert_eq!(reasons(&applied), reasons(&checked));
+    }
+    #[tokio::test]
+    async fn builder_options_take_effect() {
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(1), dec!(1.23456)),
+            Event::withdrawal(TxId(2), ClientId(1), dec!(1.23)),
+            Event::deposit(TxId(3), ClientId(1), dec!(5)),
+            Event::withdrawal(TxId(4), ClientId(1), dec!(4)),
+            Event::Dispute {
+                client: ClientId(1),
+                tx_id: TxId(3),
+            },
+            Event::Chargeback {
+                client: ClientId(1),
+                tx_id: TxId(3),
+            },
+        ];
+        let (engine, sender) = AccountingEngine::new();
+        for event in events.clone() {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+        let default = engine.process_txs().await.unwrap().results;
+        assert_eq!(dec!(-3.99544), default[&ClientId(1)].available);
+
+        let (engine, sender) = AccountingEngine::builder()
+            .channel_capacity(1)
+            .round_each_operation(2, RoundingStrategy::MidpointNearestEven)
+            .chargeback_policy(ChargebackPolicy::ClampToZero)
+            .build();
+        let processing = tokio::spawn(engine.process_txs());
+        for event in events {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+        let configured = processing.await.unwrap().unwrap().results;
+        assert_eq!(dec!(0), configured[&ClientId(1)].available);
+        assert_eq!(dec!(0), configured[&ClientId(1)].held);
+        assert!(configured[&ClientId(1)].locked);
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn results_of_thousands_of_clients() {
+        let (engine, sender) = AccountingEngine::new();
+        let processing = tokio::spawn(engine.process_txs());
+        for client in (0..5000).rev() {
+            let amount = Decimal::from(client) + dec!(1);
+            let client = ClientId(client);
+            sender.send(Event::deposit(TxId(0), client, amount)).await.unwrap();
+            sender.send(Event::withdrawal(TxId(1), client, dec!(2))).await.unwrap();
+        }
+        drop(sender);
+        let output = processing.await.unwrap().unwrap();
+        assert_eq!(5000, output.results.len());
+        assert!(output.results.keys().map(|client| client.0).eq(0..5000));
+        for (client, result) in &output.results {
+            let deposited = Decimal::from(client.0) + dec!(1);
+            let expected = if deposited < dec!(2) { deposited } else { deposited - dec!(2) };
+            assert_eq!(expected, result.available);
+        }
+        assert_eq!(1, output.rejections.len());
+        assert!(matches!(output.rejections[0].1, AccountingError::InsufficientFunds(ClientId(0))));
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn output_is_independent_of_the_interleaving_of_clients() {
+        use crate::output::{convert_output, write_output, OutputOptions};
+        use std::collections::VecDeque;
+        let streams: Vec<VecDeque<Event>> = (0..40)
+            .map(|client| {
+                let amount = Decimal::from(client) + dec!(1);
+                let charged_back = client % 2 == 0;
+                let client = ClientId(client);
+                let settle = if charged_back {
+                    Event::Chargeback { client, tx_id: TxId(0) }
+                } else {
+                    Event::Resolve { client, tx_id: TxId(0) }
+                };
+                VecDeque::from(vec![
+                    Event::deposit(TxId(0), client, amount),
+                    Event::withdrawal(TxId(1), client, dec!(3)),
+                    Event::deposit(TxId(2), client, dec!(2)),
+                    Event::Dispute { client, tx_id: TxId(0) },
+                    settle,
+                    Event::deposit(TxId(3), client, dec!(1)),
+                    Event::withdrawal(TxId(4), client, dec!(1.5)),
+                ])
+            })
+            .collect();
+        let mut seed = 0x2545_f491_u64;
+        let mut runs = vec![];
+        for _ in 0..20 {
+            let mut streams = streams.clone();
+            let mut events = vec![];
+            while streams.iter().any(|stream| !stream.is_empty()) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let remaining: Vec<_> = streams.iter_mut().filter(|s| !s.is_empty()).collect();
+                let index = seed as usize % remaining.len();
+                events.extend(remaining.into_iter().nth(index).unwrap().pop_front());
+            }
+            let output = AccountingEngine::builder()
+                .process_events(events)
+                .await
+                .unwrap();
+            let mut csv = vec![];
+            write_output(&mut csv, convert_output(output.results, OutputOptions::default()))
+                .unwrap();
+            let mut rejections: Vec<_> = output
+                .rejections
+                .iter()
+                .map(|(event, error)| format!("{:?} {:?}", event, error))
+                .collect();
+            // Only the rejections of each client keep their order
+            rejections.sort();
+            runs.push((csv, rejections));
+        }
+        assert!(!runs[0].1.is_empty());
+        assert!(runs.iter().all(|run| run == &runs[0]));
     }
 }
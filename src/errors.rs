@@ -1,17 +1,60 @@
-use crate::engine::{ClientId, TxId};
+use crate::engine::{Amount, ClientId, TxId};
+use crate::transactions::{Currency, Event};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum AccountingError {
     TransactionAlreadyExists(TxId),
     InsufficientFunds(ClientId),
     TransactionDoesntExist(TxId),
-    TransactionIsAlreadyLocked(TxId),
-    TransactionIsNotDisputed(TxId),
+    /// `line` is the csv line the transaction was read from, if any
+    TransactionIsAlreadyLocked {
+        tx_id: TxId,
+        line: Option<u64>,
+    },
+    TransactionIsNotDisputed {
+        tx_id: TxId,
+        line: Option<u64>,
+    },
+    /// Charged back transactions stay recorded but can't be disputed again
+    TransactionAlreadyChargedBack(TxId),
+    TransactionAlreadyReversed(TxId),
     AccountFrozen(ClientId),
-    TransactionDoesntBelongToClient { tx_id: TxId, client: ClientId },
+    AccountPurged(ClientId),
+    /// Only [`crate::transactions::Event::Reinstate`] lifts a freeze caused by a chargeback
+    CannotUnfreezeChargedBack(ClientId),
+    ClientEventLimitExceeded(ClientId),
+    /// An event of the client arrived after its block of sorted events ended
+    ClientNotContiguous(ClientId),
+    /// A partial dispute of more than the rest of the transaction
     InvalidAmount,
+    /// A deposit, withdrawal, partial dispute, fee or transfer of nothing
+    ZeroAmount,
+    NegativeAmount,
+    /// A deposit arrived already disputed without [`EngineConfig::locked_deposits`]
+    ///
+    /// [`EngineConfig::locked_deposits`]: crate::config::EngineConfig::locked_deposits
+    LockedDeposit(TxId),
+    /// A transfer from the client's account to itself
+    SelfTransfer(ClientId),
+    /// The operation would make the held funds of the client negative
+    WouldGoNegative(ClientId),
+    /// A balance of the client would leave the range of an amount.
+    /// The balances stay as they were.
+    AmountOverflow(ClientId),
+    /// The transaction isn't in the currency that the first deposit of the account fixed
+    CurrencyMismatch {
+        client: ClientId,
+        expected: Currency,
+        found: Currency,
+    },
+    /// The deposit or withdrawal is larger than [`EngineConfig::max_amount`]
+    ///
+    /// [`EngineConfig::max_amount`]: crate::config::EngineConfig::max_amount
+    AmountExceedsLimit { tx_id: TxId, limit: Amount },
+    /// Handling the event panicked. The account stays as it was before the event.
+    Panicked { client: ClientId, message: String },
 }
 
 impl Display for AccountingError {
@@ -21,3 +64,48 @@ impl Display for AccountingError {
 }
 
 impl Error for AccountingError {}
+
+/// Why the engine couldn't produce the output of all accounts
+#[derive(Clone, Debug)]
+pub enum EngineError {
+    /// The task of the client's account panicked outside of handling an event,
+    /// so its balances are lost. Panics while handling an event reject the event instead.
+    AccountPanicked(ClientId),
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for EngineError {}
+
+/// Logs a rejected event as a warning with its client, tx id and the reason
+pub(crate) fn log_rejection(event: &Event, error: &AccountingError) {
+    tracing::warn!(
+        client = ?event.client(),
+        tx = ?event.tx_id(),
+        error = ?error,
+        "rejected {}",
+        event.kind()
+    );
+}
+
+/// Why a csv row couldn't be turned into an event
+#[derive(Debug)]
+pub enum InputError {
+    UnknownType(String),
+    MissingAmount,
+    /// Only deposits, withdrawals and fee sweeps carry an amount
+    UnexpectedAmount,
+    InvalidCurrency(String),
+}
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for InputError {}
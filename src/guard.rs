@@ -0,0 +1,54 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+
+/// Remembers the content hashes of already processed inputs in a sidecar file
+/// so that an at-least-once pipeline can't ingest the same batch twice.
+pub struct HashGuard {
+    path: PathBuf,
+    seen: BTreeSet<String>,
+}
+impl HashGuard {
+    /// Loads the hashes recorded in `path`. A missing sidecar starts out empty.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let mut seen = BTreeSet::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        seen.insert(line.trim().to_string());
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(HashGuard { path, seen })
+    }
+    pub fn digest(content: &[u8]) -> String {
+        Sha256::digest(content)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+    pub fn contains(&self, digest: &str) -> bool {
+        self.seen.contains(digest)
+    }
+    /// Appends `digest` to the sidecar file.
+    pub fn record(&mut self, digest: String) -> std::io::Result<()> {
+        if self.seen.contains(&digest) {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", digest)?;
+        self.seen.insert(digest);
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+//! Processes transactions of client accounts, including disputes and chargebacks.
+//!
+//! Events are sent to an [`AccountingEngine`] through the sender returned by its builder.
+//! Once the sender is dropped, [`AccountingEngine::process_txs`] returns the balances
+//! of every client and the events that were rejected.
+//!
+//! ```
+//! use accounting_engine::{AccountingEngine, ClientId, Event, TxId};
+//! use rust_decimal_macros::dec;
+//!
+//! # tokio_test();
+//! # #[tokio::main]
+//! # async fn tokio_test() {
+//! let (engine, sender) = AccountingEngine::builder().build();
+//! sender.send(Event::deposit(TxId(1), ClientId(1), dec!(2.5))).await.unwrap();
+//! drop(sender);
+//! let result = engine.process_txs().await.unwrap().results;
+//! assert_eq!(dec!(2.5), result[&ClientId(1)].available);
+//! # }
+//! ```
+
+mod account;
+pub mod certificate;
+pub mod config;
+pub mod engine;
+pub mod errors;
+pub mod guard;
+pub mod metrics;
+pub mod output;
+mod sequential;
+pub mod transactions;
+
+pub use engine::{
+    process_events, AccountingEngine, AccountingEngineBuilder, AccountingResult, Amount, ClientId,
+    EngineOutput, EngineSnapshot, ReconciliationReport, TxId,
+};
+pub use errors::{AccountingError, EngineError};
+pub use transactions::{Event, Transaction};
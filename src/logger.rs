@@ -0,0 +1,39 @@
+use tracing_subscriber::EnvFilter;
+
+/// Parses the directives of `RUST_LOG`, like `warn` or `accounting_engine=debug`.
+/// Logging stays off without valid ones.
+fn filter(rust_log: Option<&str>) -> EnvFilter {
+    rust_log
+        .and_then(|directives| EnvFilter::try_new(directives.trim()).ok())
+        .unwrap_or_else(|| EnvFilter::new("off"))
+}
+
+/// Installs a subscriber that writes the events allowed by `RUST_LOG` to the standard error
+pub fn init() {
+    let filter = filter(std::env::var("RUST_LOG").ok().as_deref());
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::logger::filter;
+    use tracing_subscriber::filter::LevelFilter;
+
+    #[test]
+    fn filter_from_rust_log() {
+        assert_eq!(
+            Some(LevelFilter::WARN),
+            filter(Some("warn")).max_level_hint()
+        );
+        assert_eq!(
+            Some(LevelFilter::DEBUG),
+            filter(Some("DEBUG")).max_level_hint()
+        );
+        let nonsense = filter(Some("accounting_engine=nonsense"));
+        assert_eq!(Some(LevelFilter::OFF), nonsense.max_level_hint());
+        assert_eq!(Some(LevelFilter::OFF), filter(None).max_level_hint());
+    }
+}
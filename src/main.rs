@@ -1,125 +1,1191 @@
-use std::collections::BTreeMap;
-use crate::engine::{AccountingEngine, AccountingResult, Amount, ClientId, TxId};
-use crate::transactions::{Event, Transaction};
+use std::collections::{BTreeMap, BTreeSet};
+use accounting_engine::certificate::Certificate;
+use crate::cli::{InputFormat, InputOptions, Options};
+use accounting_engine::config::EventCounters;
+use accounting_engine::errors::InputError;
+use accounting_engine::engine::{
+    conservation_discrepancy, AccountingEngine, AccountingEngineBuilder, AccountingResult, Amount,
+    ClientId, TxId,
+};
+use accounting_engine::guard::HashGuard;
+use accounting_engine::output::{convert_output_in_order, split_output, write_results, OutputOptions};
+use accounting_engine::transactions::{Event, Transaction, TransactionType};
 use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, Sender};
 
-mod account;
-mod engine;
-mod errors;
-mod transactions;
+mod cli;
+mod logger;
+#[cfg(unix)]
+mod socket;
+mod verify;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    if let Some(input_path) = std::env::args().nth(1) {
-        match csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(input_path) {
-            Ok(mut reader) => {
-                let (engine, sender) = AccountingEngine::new();
-                for entry in reader.deserialize() {
-                    let record: Input = entry?;
-                    if let Some(event) = convert_input(record) {
-                        sender.send(event)?;
-                    }
+    logger::init();
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if options.print_schema {
+        print!("{}", schema(&options.input_options)?);
+        return Ok(());
+    }
+    let output_options = OutputOptions {
+        pending: options.clearing_delay > 0,
+        total: !options.no_total,
+        disputed_count: options.disputed_count,
+        truncate: options.truncate.then_some(4),
+        scale: options.scale,
+    };
+    if let Some(path) = options.uds {
+        #[cfg(unix)]
+        {
+            let listener = tokio::net::UnixListener::bind(&path)?;
+            let builder = || {
+                AccountingEngine::builder()
+                    .clearing_delay(options.clearing_delay)
+                    .sorted_by_client(options.input_options.sorted_by_client)
+            };
+            return Ok(socket::serve(listener, builder, options.format, output_options).await?);
+        }
+        #[cfg(not(unix))]
+        {
+            eprintln!("--uds {} needs unix sockets", path.display());
+            std::process::exit(2);
+        }
+    } else {
+        let inputs = match options.inputs {
+            inputs if inputs.is_empty() => vec![STDIN.to_string()],
+            inputs => inputs,
+        };
+        let mut paths = vec![];
+        for input in &inputs {
+            paths.extend(expand_input(input)?);
+        }
+        let input = inputs.join(" ");
+        let mut guard = match options.dedup {
+            Some(sidecar) => Some(HashGuard::open(sidecar)?),
+            None => None,
+        };
+        let mut builder = AccountingEngine::builder()
+            .clearing_delay(options.clearing_delay)
+            .sorted_by_client(options.input_options.sorted_by_client);
+        let counters = Arc::new(EventCounters::default());
+        if options.certificate.is_some() {
+            builder = builder.count_events(counters.clone());
+        }
+        let first_seen = Arc::new(Mutex::new(vec![]));
+        if options.first_seen_order {
+            builder = builder.record_first_seen(first_seen.clone());
+        }
+        let (echo, mut echoed) = unbounded_channel();
+        if options.echo_events {
+            builder = builder.echo_events(echo);
+        } else {
+            drop(echo);
+        }
+        let output =
+            process_files(&paths, guard.as_mut(), builder, &options.input_options).await?;
+        if options.echo_events {
+            let mut events = vec![];
+            while let Some(event) = echoed.recv().await {
+                events.push(event);
+            }
+            events.sort_by_key(|(sequence, _)| *sequence);
+            let events = events.into_iter().map(|(_, event)| event);
+            write_events(std::io::stderr(), events)?;
+        }
+        let rejected = output.as_ref().map_or(0, |output| output.rejected);
+        let output = output.map(|output| output.results);
+        if let (Some(path), Some(result)) = (&options.certificate, &output) {
+            Certificate::new(&counters, result)?.write(path)?;
+        }
+        if let (true, Some(result)) = (options.verify_conservation, &output) {
+            let discrepancy = conservation_discrepancy(result);
+            if !discrepancy.is_zero() {
+                eprintln!("Funds aren't conserved: the totals are off by {}", discrepancy);
+                std::process::exit(1);
+            }
+        }
+        if let Some(expected_path) = options.expected {
+            let expected = verify::read_expected(reader_builder().from_path(&expected_path)?)?;
+            match verify::verify(&output.unwrap_or_default(), &expected) {
+                Some(mismatch) => {
+                    eprintln!("{} doesn't match {}: {}", input, expected_path, mismatch);
+                    std::process::exit(1);
                 }
-                drop(sender);
-                let output = engine.process_txs().await;
-                print_output(convert_output(output));
+                None => eprintln!("{} matches {}", input, expected_path),
             }
-            Err(e) => {
-                eprintln!("Couldn't create reader: {:?}", e);
+        } else if let Some(output) = output {
+            let order = first_seen.lock().unwrap().clone();
+            let output = convert_output_in_order(output, &order, output_options);
+            let written = match options.split_by_client {
+                Some(dir) => split_output(&dir, output),
+                None => write_results(std::io::stdout(), &options.format, output, output_options),
+            };
+            if let Err(e) = written {
+                eprintln!("failed to write output: {}", e);
+                std::process::exit(1);
             }
         }
+        if rejected > 0 {
+            eprintln!("{} rows or events were rejected", rejected);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+/// The input path that reads from the standard input
+const STDIN: &str = "-";
+/// Expands a glob pattern into the sorted list of matching files.
+/// A pattern without matches is passed through so that opening it reports the error.
+#[cfg(feature = "glob")]
+fn expand_input(pattern: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut paths = vec![];
+    for path in glob::glob(pattern)? {
+        paths.push(path?.to_string_lossy().into_owned());
+    }
+    paths.sort();
+    if paths.is_empty() {
+        return Ok(vec![pattern.to_string()]);
+    }
+    if paths.len() > 1 || paths[0] != pattern {
+        eprintln!("{} matched {} files: {}", pattern, paths.len(), paths.join(", "));
+    }
+    Ok(paths)
+}
+#[cfg(not(feature = "glob"))]
+fn expand_input(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(vec![path.to_string()])
+}
+/// Reads csv whose columns are matched by their header, so their order doesn't matter
+/// and unknown columns are ignored
+fn reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.trim(csv::Trim::All);
+    builder
+}
+/// The balances after [`process_files`]
+#[derive(Debug)]
+struct Processed {
+    results: BTreeMap<ClientId, AccountingResult>,
+    /// The rows that were skipped and the events the engine rejected
+    rejected: usize,
+}
+/// Processes the csv files at `paths` in order through one engine.
+/// Returns `None` if the `guard` skipped every file.
+///
+/// The engine runs in its own task while the rows are read, so events are handled
+/// as they arrive instead of after the whole input was buffered.
+/// Once the channels to the engine are full, reading waits for the accounts to catch up.
+async fn process_files(
+    paths: &[String],
+    mut guard: Option<&mut HashGuard>,
+    builder: AccountingEngineBuilder,
+    input_options: &InputOptions,
+) -> Result<Option<Processed>, Box<dyn Error>> {
+    let (engine, sender) = builder.build();
+    let processing = tokio::spawn(engine.process_txs());
+    let mut processed = 0;
+    let mut state = FeedState::default();
+    for path in paths {
+        if feed_file(path, guard.as_deref_mut(), input_options, &sender, &mut state).await? {
+            processed += 1;
+        } else {
+            eprintln!(
+                "Skipping {}: a file with identical content was already processed",
+                path
+            );
+        }
+    }
+    state.flush(&sender).await?;
+    drop(sender);
+    let output = processing.await??;
+    let processed = (processed > 0).then_some(Processed {
+        rejected: state.bad_rows.len() + output.rejections.len(),
+        results: output.results,
+    });
+    Ok(processed)
+}
+/// Sends the events of the file at `path`, or of the standard input for [`STDIN`],
+/// to the engine. With a `guard`, a file whose content hash was already recorded is skipped
+/// and `false` is returned.
+async fn feed_file(
+    path: &str,
+    guard: Option<&mut HashGuard>,
+    input_options: &InputOptions,
+    sender: &Sender<Event>,
+    state: &mut FeedState,
+) -> Result<bool, Box<dyn Error>> {
+    let guard = match guard {
+        Some(guard) => guard,
+        None if input_options.format == InputFormat::Csv && path == STDIN => {
+            let reader = reader_builder().from_reader(std::io::stdin());
+            feed_csv(reader, input_options, sender, state).await?;
+            return Ok(true);
+        }
+        None if input_options.format == InputFormat::Csv => {
+            let reader = reader_builder().from_path(path)?;
+            feed_csv(reader, input_options, sender, state).await?;
+            return Ok(true);
+        }
+        None => {
+            feed_content(&read_input(path)?, input_options, sender, state).await?;
+            return Ok(true);
+        }
+    };
+    let content = read_input(path)?;
+    let digest = HashGuard::digest(&content);
+    if guard.contains(&digest) {
+        return Ok(false);
+    }
+    feed_content(&content, input_options, sender, state).await?;
+    guard.record(digest)?;
+    Ok(true)
+}
+fn read_input(path: &str) -> std::io::Result<Vec<u8>> {
+    if path == STDIN {
+        let mut content = vec![];
+        std::io::Read::read_to_end(&mut std::io::stdin(), &mut content)?;
+        Ok(content)
     } else {
-        eprintln!("Missing path to csv file");
+        std::fs::read(path)
+    }
+}
+/// Sends the events of `content` in the [`InputOptions::format`] to the engine
+async fn feed_content(
+    content: &[u8],
+    input_options: &InputOptions,
+    sender: &Sender<Event>,
+    state: &mut FeedState,
+) -> Result<(), Box<dyn Error>> {
+    match input_options.format {
+        InputFormat::Csv => {
+            let reader = reader_builder().from_reader(content);
+            feed_csv(reader, input_options, sender, state).await
+        }
+        InputFormat::Json => feed_json(content, input_options, sender, state).await,
+    }
+}
+/// What [`feed_record`] remembers across the files of one engine
+#[derive(Default)]
+struct FeedState {
+    /// Lines of the rows that were skipped
+    bad_rows: Vec<String>,
+    /// The client of the previous row and the clients before it, see
+    /// [`InputOptions::sorted_by_client`]
+    current_client: Option<ClientId>,
+    finished_clients: BTreeSet<ClientId>,
+    /// Transactions dropped by [`InputOptions::clients`]
+    filtered_txs: BTreeSet<(ClientId, TxId)>,
+    /// The events held back by [`InputOptions::order_by_time`] with their timestamps
+    buffered: Vec<(Option<u64>, Event)>,
+    /// The timestamp of the previous row, which rows without one take over
+    last_timestamp: Option<u64>,
+}
+impl FeedState {
+    /// Sends the events buffered by [`InputOptions::order_by_time`] ordered by their timestamps.
+    /// Events with the same timestamp keep the order of the input.
+    async fn flush(&mut self, sender: &Sender<Event>) -> Result<(), Box<dyn Error>> {
+        let mut buffered = std::mem::take(&mut self.buffered);
+        buffered.sort_by_key(|(timestamp, _)| *timestamp);
+        for (_, event) in buffered {
+            sender.send(event).await?;
+        }
+        Ok(())
+    }
+}
+async fn feed_csv<R: std::io::Read>(
+    mut reader: csv::Reader<R>,
+    input_options: &InputOptions,
+    sender: &Sender<Event>,
+    state: &mut FeedState,
+) -> Result<(), Box<dyn Error>> {
+    let headers = reader.headers()?.clone();
+    let mut row = csv::StringRecord::new();
+    while reader.read_record(&mut row)? {
+        let record = row
+            .deserialize::<Input>(Some(&headers))
+            .map_err(|e| e.to_string());
+        let line = row.position().map_or(0, |position| position.line());
+        feed_record(record, line, input_options, sender, state).await?;
+    }
+    Ok(())
+}
+/// Reads a json array of records, or one record per line. Records have the fields of
+/// the csv columns. The lines of the records in an array are their positions in it.
+async fn feed_json(
+    content: &[u8],
+    input_options: &InputOptions,
+    sender: &Sender<Event>,
+    state: &mut FeedState,
+) -> Result<(), Box<dyn Error>> {
+    let content = std::str::from_utf8(content)?;
+    let records: Vec<(u64, serde_json::Result<Input>)> =
+        if content.trim_start().starts_with('[') {
+            let values: Vec<serde_json::Value> = serde_json::from_str(content)?;
+            (1..).zip(values.into_iter().map(serde_json::from_value)).collect()
+        } else {
+            (1..)
+                .zip(content.lines())
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(number, line)| (number, serde_json::from_str(line)))
+                .collect()
+        };
+    for (line, record) in records {
+        let record = record.map_err(|e| e.to_string());
+        feed_record(record, line, input_options, sender, state).await?;
     }
     Ok(())
 }
-fn convert_input(entry: Input) -> Option<Event> {
-    match entry.tx_type.as_str() {
-        "deposit" => {
-            if entry.amount.is_none() {
-                return None;
+/// Records that fail to deserialize, to pass [`limit_scale`] or to convert to an event
+/// are skipped and collected in the `state`.
+/// Once `max_errors` of them accumulated the input is aborted.
+async fn feed_record(
+    record: Result<Input, String>,
+    line: u64,
+    input_options: &InputOptions,
+    sender: &Sender<Event>,
+    state: &mut FeedState,
+) -> Result<(), Box<dyn Error>> {
+    let record = match record.and_then(|record| limit_scale(record, input_options)) {
+        Ok(record) => record,
+        Err(e) => return skip_row(line, e, input_options, state),
+    };
+    if input_options.sorted_by_client && state.current_client != Some(record.client) {
+        if state.finished_clients.contains(&record.client) {
+            return Err(format!(
+                "Input isn't sorted by client: client {} reappears on line {}",
+                record.client, line
+            )
+            .into());
+        }
+        state.finished_clients.extend(state.current_client.replace(record.client));
+    }
+    let timestamp = record.timestamp.or(state.last_timestamp);
+    state.last_timestamp = timestamp;
+    let event = match convert_input(record, Some(line), input_options) {
+        Ok(event) => event,
+        Err(e) => return skip_row(line, e, input_options, state),
+    };
+    if let Some(filter) = &input_options.clients {
+        match &event {
+            Event::Deposit(tx) | Event::Withdrawal(tx) if !filter.allows(tx.client) => {
+                state.filtered_txs.insert((tx.client, tx.id));
             }
-            return Some(Event::Deposit(Transaction {
-                id: entry.tx,
-                client: entry.client,
-                amount: entry.amount.unwrap(),
-                is_locked: false,
-            }));
+            Event::Dispute { client, tx_id }
+            | Event::Resolve { client, tx_id }
+            | Event::Chargeback { client, tx_id }
+            | Event::PartialDispute { client, tx_id, .. }
+                if state.filtered_txs.contains(&(*client, *tx_id)) =>
+            {
+                eprintln!(
+                    "Line {}: transaction {} belongs to a filtered out client",
+                    line, tx_id
+                );
+            }
+            _ => {}
+        }
+        if !event.client().is_none_or(|client| filter.allows(client)) {
+            return Ok(());
         }
-        "withdrawal" => {
-            if entry.amount.is_none() {
-                return None;
+    }
+    if input_options.order_by_time {
+        state.buffered.push((timestamp, event));
+        return Ok(());
+    }
+    sender.send(event).await?;
+    Ok(())
+}
+/// Skips the row on `line` because of `error`, or aborts the input once it was the
+/// `max_errors`th bad row
+fn skip_row(
+    line: u64,
+    error: impl std::fmt::Display,
+    input_options: &InputOptions,
+    state: &mut FeedState,
+) -> Result<(), Box<dyn Error>> {
+    let bad_rows = &mut state.bad_rows;
+    bad_rows.push(format!("line {}", line));
+    if input_options.max_errors == Some(bad_rows.len()) {
+        return Err(format!(
+            "Aborting after {} bad rows: {}",
+            bad_rows.len(),
+            bad_rows.join(", ")
+        )
+        .into());
+    }
+    eprintln!("Skipping row on line {}: {}", line, error);
+    Ok(())
+}
+/// Rejects amounts with more decimal places than [`InputOptions::max_scale`],
+/// or rounds them with [`InputOptions::round_scale`].
+fn limit_scale(mut entry: Input, options: &InputOptions) -> Result<Input, String> {
+    if let (Some(max), Some(amount)) = (options.max_scale, entry.amount.as_mut()) {
+        if amount.scale() > max {
+            if !options.round_scale {
+                return Err(format!("amount {} has more than {} decimal places", amount, max));
             }
-            return Some(Event::Withdrawal(Transaction {
-                id: entry.tx,
-                client: entry.client,
-                amount: entry.amount.unwrap(),
-                is_locked: false,
-            }));
+            *amount = amount.round_dp(max);
         }
-        "dispute" => {
-            if entry.amount.is_some() {
-                return None;
+    }
+    Ok(entry)
+}
+/// Converts a csv row into an event. Deposits and withdrawals remember the `line` of the row.
+fn convert_input(
+    entry: Input,
+    line: Option<u64>,
+    options: &InputOptions,
+) -> Result<Event, InputError> {
+    let currency = match entry.currency.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(code) => Some(code.parse().map_err(InputError::InvalidCurrency)?),
+    };
+    let transaction = |amount| Transaction {
+        line,
+        currency,
+        timestamp: entry.timestamp,
+        ..Transaction::new(entry.tx, entry.client, amount)
+    };
+    let amount = || entry.amount.ok_or(InputError::MissingAmount);
+    let no_amount = || match entry.amount {
+        Some(_) => Err(InputError::UnexpectedAmount),
+        None => Ok(()),
+    };
+    match entry.tx_type {
+        TransactionType::Transaction if options.signed_amounts => {
+            let amount = amount()?;
+            let tx = transaction(amount.abs());
+            if amount.is_sign_negative() {
+                Ok(Event::Withdrawal(tx))
+            } else {
+                Ok(Event::Deposit(tx))
             }
-            return Some(Event::Dispute {
+        }
+        TransactionType::Deposit => {
+            Ok(Event::Deposit(transaction(amount()?)))
+        }
+        TransactionType::Withdrawal => {
+            Ok(Event::Withdrawal(transaction(amount()?)))
+        }
+        TransactionType::Dispute => {
+            no_amount()?;
+            Ok(Event::Dispute {
                 client: entry.client,
                 tx_id: entry.tx,
-            });
+            })
         }
-        "resolve" => {
-            if entry.amount.is_some() {
-                return None;
-            }
-            return Some(Event::Resolve {
+        TransactionType::Resolve => {
+            no_amount()?;
+            Ok(Event::Resolve {
                 client: entry.client,
                 tx_id: entry.tx,
-            });
+            })
         }
-        "chargeback" => {
-            if entry.amount.is_some() {
-                return None;
-            }
-            return Some(Event::Chargeback {
+        TransactionType::Chargeback => {
+            no_amount()?;
+            Ok(Event::Chargeback {
                 client: entry.client,
                 tx_id: entry.tx,
-            });
+            })
         }
+        TransactionType::PartialDispute => Ok(Event::PartialDispute {
+            client: entry.client,
+            tx_id: entry.tx,
+            amount: amount()?,
+        }),
+        TransactionType::Freeze => no_amount().map(|_| Event::Freeze {
+            client: entry.client,
+        }),
+        TransactionType::Unfreeze => no_amount().map(|_| Event::Unfreeze {
+            client: entry.client,
+        }),
+        TransactionType::Reinstate => no_amount().map(|_| Event::Reinstate {
+            client: entry.client,
+        }),
+        TransactionType::FeeSweep => Ok(Event::FeeSweep { amount: amount()? }),
+        other => Err(InputError::UnknownType(other.to_string())),
+    }
+}
+/// Turns an event back into its input row
+fn convert_event(event: &Event) -> Input {
+    let amount = match event {
+        Event::Deposit(tx) | Event::Withdrawal(tx) => Some(tx.amount),
+        Event::PartialDispute { amount, .. } | Event::FeeSweep { amount } => Some(*amount),
         _ => None,
+    };
+    Input {
+        tx_type: event.transaction_type(),
+        client: event.client().unwrap_or_default(),
+        tx: event.tx_id().unwrap_or_default(),
+        amount: amount.map(|amount| amount.normalize()),
+        currency: None,
+        timestamp: None,
     }
 }
-fn convert_output(result: BTreeMap<ClientId, AccountingResult>) -> Vec<Output> {
-    let mut output = vec![];
-    for (client, entry) in result {
-        output.push(Output {
-            client: client.to_string(),
-            available: entry.available.normalize(),
-            held: entry.held.normalize(),
-            total: entry.total.normalize(),
-            locked: entry.locked
-        })
+fn write_events<W: std::io::Write>(
+    writer: W,
+    events: impl IntoIterator<Item = Event>,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    for event in events {
+        wtr.serialize(convert_event(&event))?;
     }
-    output
+    wtr.flush()?;
+    Ok(())
 }
-fn print_output(output: Vec<Output>) {
-    let mut wtr = csv::Writer::from_writer(std::io::stdout());
-    for entry in output {
-        wtr.serialize(entry).unwrap();
+/// Describes the columns of the input, which types [`convert_input`] accepts with or without
+/// an amount and an example row of each
+fn schema(options: &InputOptions) -> Result<String, Box<dyn Error>> {
+    let mut description = String::from(
+        "Columns: type, client, tx, amount in any order, optionally currency and timestamp\n",
+    );
+    let mut examples = csv::Writer::from_writer(vec![]);
+    for tx_type in TransactionType::ALL {
+        let convert = |amount| {
+            let entry = Input {
+                tx_type,
+                client: ClientId(1),
+                tx: TxId(1),
+                amount,
+                currency: None,
+                timestamp: None,
+            };
+            convert_input(entry, None, options).ok()
+        };
+        let (with_amount, without_amount) = (convert(Some(Amount::new(15, 1))), convert(None));
+        let amount = match (&with_amount, &without_amount) {
+            (Some(_), None) => "requires an amount",
+            (None, Some(_)) => "takes no amount",
+            (Some(_), Some(_)) => "takes an optional amount",
+            (None, None) => continue,
+        };
+        description += &format!("{}: {}\n", tx_type, amount);
+        if let Some(event) = with_amount.or(without_amount) {
+            examples.serialize(Input {
+                tx_type,
+                ..convert_event(&event)
+            })?;
+        }
     }
-    wtr.flush().unwrap();
+    let examples = String::from_utf8(examples.into_inner()?)?;
+    Ok(format!("{}\nExample:\n{}", description, examples))
 }
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Input {
-    #[serde(rename = "type")]
-    tx_type: String,
+    #[serde(rename = "type", deserialize_with = "deserialize_type")]
+    tx_type: TransactionType,
     client: ClientId,
     tx: TxId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
     amount: Option<Amount>,
+    /// An optional column with the currency code of deposits and withdrawals
+    #[serde(default, skip_serializing)]
+    currency: Option<String>,
+    /// An optional column with the seconds since the unix epoch, see
+    /// [`InputOptions::order_by_time`]
+    #[serde(default, skip_serializing)]
+    timestamp: Option<u64>,
+}
+/// Parses the types of [`TransactionType::is_input`] only
+fn deserialize_type<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TransactionType, D::Error> {
+    let name: String = serde::Deserialize::deserialize(deserializer)?;
+    match name.parse::<TransactionType>() {
+        Ok(tx_type) if tx_type.is_input() => Ok(tx_type),
+        _ => Err(serde::de::Error::custom(format!(
+            "unknown transaction type `{}`",
+            name
+        ))),
+    }
+}
+/// Parses finite decimal amounts only, so tokens like `inf` or `NaN`
+/// are reported as an invalid row instead of failing the whole file.
+fn deserialize_amount<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Amount>, D::Error> {
+    let raw: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    match raw.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(raw) => raw.parse().map(Some).map_err(|_| {
+            serde::de::Error::custom(format!("amount `{}` is not a finite decimal number", raw))
+        }),
+    }
 }
-#[derive(Debug, serde::Serialize)]
-pub struct Output {
-    client: String,
-    available: Amount,
-    held: Amount,
-    total: Amount,
-    locked: bool,
+
+#[cfg(test)]
+mod test {
+    use crate::cli::{ClientFilter, InputFormat, InputOptions, Options};
+    use accounting_engine::engine::{AccountingEngine, AccountingResult, ClientId, TxId};
+    use accounting_engine::guard::HashGuard;
+    use accounting_engine::transactions::{write_jsonl, Event, Transaction, TransactionType};
+    use accounting_engine::output::{
+        convert_output, write_output, write_results, OutputFormat, OutputOptions,
+    };
+    use crate::{convert_input, process_files, reader_builder, schema, write_events, Input};
+    use accounting_engine::errors::InputError;
+    use tokio::sync::mpsc::unbounded_channel;
+    use rust_decimal_macros::dec;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::path::Path;
+
+    async fn process(paths: &[String]) -> BTreeMap<ClientId, AccountingResult> {
+        let builder = AccountingEngine::builder();
+        let options = InputOptions::default();
+        let result = process_files(paths, None, builder, &options).await;
+        result.unwrap().unwrap().results
+    }
+    fn write_csv(path: &Path, rows: &str) -> String {
+        std::fs::write(path, format!("type,client,tx,amount\n{}", rows)).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn dedup_guard_skips_same_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = vec![write_csv(&dir.path().join("tx.csv"), "deposit,1,1,2.5\n")];
+        let sidecar = dir.path().join("processed.sha256");
+
+        let mut guard = HashGuard::open(&sidecar).unwrap();
+        let builder = AccountingEngine::builder();
+        let options = InputOptions::default();
+        let first = process_files(&input, Some(&mut guard), builder, &options).await;
+        let first = first.unwrap();
+        assert_eq!(dec!(2.5), first.unwrap().results.get(&ClientId(1)).unwrap().available);
+
+        let mut guard = HashGuard::open(&sidecar).unwrap();
+        let builder = AccountingEngine::builder();
+        let second = process_files(&input, Some(&mut guard), builder, &options).await;
+        assert!(second.unwrap().is_none());
+        assert!(!process(&input).await.is_empty());
+    }
+    #[cfg(feature = "glob")]
+    #[tokio::test]
+    async fn glob_processes_matches_in_sorted_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write_csv(&dir.path().join("c.csv"), "withdrawal,1,3,1\n");
+        write_csv(&dir.path().join("a.csv"), "deposit,1,1,2\ndeposit,2,2,5\n");
+        write_csv(&dir.path().join("b.csv"), "dispute,2,2,\n");
+        write_csv(&dir.path().join("ignored.txt"), "deposit,3,4,1\n");
+        let pattern = format!("{}/*.csv", dir.path().to_str().unwrap());
+
+        let paths = crate::expand_input(&pattern).unwrap();
+        assert_eq!(3, paths.len());
+        assert!(paths[0].ends_with("a.csv") && paths[2].ends_with("c.csv"));
+        let result = process(&paths).await;
+        assert_eq!(dec!(1), result.get(&ClientId(1)).unwrap().available);
+        assert_eq!(dec!(5), result.get(&ClientId(2)).unwrap().held);
+        assert!(!result.contains_key(&ClientId(3)));
+    }
+    #[tokio::test]
+    async fn files_are_processed_as_one_ledger() {
+        let dir = tempfile::tempdir().unwrap();
+        let day = |name, rows| write_csv(&dir.path().join(name), rows);
+        let whole = day(
+            "whole.csv",
+            "deposit,1,1,5\ndeposit,2,2,3\nwithdrawal,1,3,2\ndispute,2,2,\nchargeback,2,2,\n",
+        );
+        let first = day("monday.csv", "deposit,1,1,5\ndeposit,2,2,3\n");
+        let second = day("tuesday.csv", "withdrawal,1,3,2\ndispute,2,2,\nchargeback,2,2,\n");
+
+        let args = [first.clone(), second.clone()];
+        let options = Options::parse(args.iter().cloned()).unwrap();
+        assert_eq!(args.to_vec(), options.inputs);
+        let split = process(&options.inputs).await;
+        assert_eq!(process(&[whole]).await, split);
+        assert_eq!(dec!(3), split[&ClientId(1)].available);
+        assert!(split[&ClientId(2)].locked);
+        // The rows of the second file don't make sense before those of the first
+        let reversed = process(&[second, first]).await;
+        assert_ne!(split, reversed);
+
+        let verify = ["verify", "a.csv", "b.csv", "expected.csv"].map(String::from);
+        let options = Options::parse(verify).unwrap();
+        assert_eq!(vec!["a.csv", "b.csv"], options.inputs);
+        assert_eq!(Some("expected.csv".to_string()), options.expected);
+        assert!(Options::parse(["verify", "a.csv"].map(String::from)).is_err());
+    }
+    #[test]
+    fn dumped_events_are_read_back_as_input() {
+        let rows = "type,client,tx,amount,currency,timestamp\n\
+                    deposit,1,1,1.5,EUR,10\nwithdrawal,1,2,0.5,,\ndispute,1,1,,,\n\
+                    resolve,1,1,,,\nchargeback,1,1,,,\npartial_dispute,1,1,0.5,,\n";
+        let options = InputOptions::default();
+        let events: Vec<_> = reader_builder()
+            .from_reader(rows.as_bytes())
+            .deserialize()
+            .map(|row| convert_input(row.unwrap(), None, &options).unwrap())
+            .collect();
+        let mut jsonl = vec![];
+        write_jsonl(&mut jsonl, &events).unwrap();
+        let read: Vec<_> = String::from_utf8(jsonl)
+            .unwrap()
+            .lines()
+            .map(|line| convert_input(serde_json::from_str(line).unwrap(), None, &options).unwrap())
+            .collect();
+        assert_eq!(events, read);
+    }
+    #[tokio::test]
+    async fn empty_inputs_write_only_the_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.csv");
+        std::fs::write(&empty, "").unwrap();
+        let header_only = write_csv(&dir.path().join("header.csv"), "");
+        for input in [empty.to_str().unwrap().to_string(), header_only] {
+            let result = process(&[input]).await;
+            assert!(result.is_empty());
+            let mut written = vec![];
+            let options = OutputOptions::default();
+            let output = convert_output(result, options);
+            write_results(&mut written, &OutputFormat::Csv, output, options).unwrap();
+            assert_eq!("client,available,held,total,locked\n", String::from_utf8(written).unwrap());
+        }
+    }
+    #[test]
+    fn schema_lists_every_transaction_type() {
+        let options = Options::parse(["--print-schema".to_string()]).unwrap();
+        assert!(options.print_schema);
+        let schema = schema(&options.input_options).unwrap();
+        for line in [
+            "deposit: requires an amount",
+            "withdrawal: requires an amount",
+            "dispute: takes no amount",
+            "resolve: takes no amount",
+            "chargeback: takes no amount",
+        ] {
+            assert!(schema.lines().any(|listed| listed == line), "{}", line);
+        }
+        let (_, example) = schema.split_once("Example:\n").unwrap();
+        let mut rows = reader_builder().from_reader(example.as_bytes());
+        let rows: Vec<Input> = rows.deserialize().map(Result::unwrap).collect();
+        assert_eq!(10, rows.len());
+        for row in rows {
+            convert_input(row, None, &options.input_options).unwrap();
+        }
+    }
+    #[tokio::test]
+    async fn echo_accepted_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,2.50\n\
+                    withdrawal,1,2,5\n\
+                    deposit,2,3,1\n\
+                    dispute,1,4,\n\
+                    dispute,1,1,\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let (echo, mut echoed) = unbounded_channel();
+        let builder = AccountingEngine::builder().echo_events(echo);
+        process_files(&input, None, builder, &InputOptions::default())
+            .await
+            .unwrap();
+        let mut events = vec![];
+        while let Some(event) = echoed.recv().await {
+            events.push(event);
+        }
+        events.sort_by_key(|(sequence, _)| *sequence);
+        let events: Vec<_> = events.into_iter().map(|(_, event)| event).collect();
+        assert_eq!(
+            vec![
+                Event::Deposit(Transaction {
+                    line: Some(2),
+                    ..Transaction::new(TxId(1), ClientId(1), dec!(2.5))
+                }),
+                Event::Deposit(Transaction {
+                    line: Some(4),
+                    ..Transaction::new(TxId(3), ClientId(2), dec!(1))
+                }),
+                Event::Dispute { client: ClientId(1), tx_id: TxId(1) },
+            ],
+            events
+        );
+        let mut written = vec![];
+        write_events(&mut written, events).unwrap();
+        assert_eq!(
+            "type,client,tx,amount\ndeposit,1,1,2.5\ndeposit,2,3,1\ndispute,1,1,\n",
+            String::from_utf8(written).unwrap()
+        );
+    }
+    #[tokio::test]
+    async fn non_finite_amounts_skip_their_row() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,inf\n\
+                    deposit,1,2,NaN\n\
+                    deposit,1,3,2\n\
+                    withdrawal,1,4,-inf\n\
+                    withdrawal,1,5,0.5\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let result = process(&input).await;
+        assert_eq!(dec!(1.5), result.get(&ClientId(1)).unwrap().available);
+
+        let csv = "type,client,tx,amount\ndeposit,1,1,NaN\n";
+        let mut reader = crate::reader_builder().from_reader(csv.as_bytes());
+        let error = reader.deserialize::<crate::Input>().next().unwrap().unwrap_err();
+        assert!(error.to_string().contains("amount `NaN` is not a finite decimal number"));
+
+        for tx_type in ["depositt", "reverse", "transfer", "query"] {
+            let csv = format!("type,client,tx,amount\n{},1,1,1\n", tx_type);
+            let mut reader = crate::reader_builder().from_reader(csv.as_bytes());
+            let error = reader.deserialize::<crate::Input>().next().unwrap().unwrap_err();
+            let expected = format!("unknown transaction type `{}`", tx_type);
+            assert!(error.to_string().contains(&expected), "{}", error);
+        }
+    }
+    #[tokio::test]
+    async fn abort_after_max_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,x\n\
+                    deposit,1,2,1\n\
+                    deposit,1,3,x\n\
+                    deposit,1,4,1\n\
+                    deposit,1,5,x\n\
+                    deposit,1,6,x\n\
+                    deposit,1,7,x\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let options = InputOptions {
+            max_errors: Some(3),
+            ..Default::default()
+        };
+        let builder = AccountingEngine::builder();
+        let result = process_files(&input, None, builder, &options).await;
+        assert_eq!(
+            "Aborting after 3 bad rows: line 2, line 4, line 6",
+            result.unwrap_err().to_string()
+        );
+    }
+    #[tokio::test]
+    async fn rows_that_arent_events_count_as_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,1\n\
+                    transaction,1,2,1\n\
+                    deposit,1,3,x\n\
+                    dispute,1,1,1\n\
+                    deposit,1,4,1\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let options = InputOptions {
+            max_errors: Some(3),
+            ..Default::default()
+        };
+        let builder = AccountingEngine::builder();
+        let result = process_files(&input, None, builder, &options).await;
+        assert_eq!(
+            "Aborting after 3 bad rows: line 3, line 4, line 5",
+            result.unwrap_err().to_string()
+        );
+    }
+    #[tokio::test]
+    async fn input_scale_is_strict_or_rounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = vec![write_csv(
+            &dir.path().join("tx.csv"),
+            "deposit,1,1,1.23456\ndeposit,1,2,1\n",
+        )];
+        let mut options = InputOptions {
+            max_scale: Some(4),
+            ..Default::default()
+        };
+        let builder = AccountingEngine::builder();
+        let strict = process_files(&input, None, builder, &options).await;
+        assert_eq!(dec!(1), strict.unwrap().unwrap().results.get(&ClientId(1)).unwrap().available);
+
+        options.round_scale = true;
+        let builder = AccountingEngine::builder();
+        let lenient = process_files(&input, None, builder, &options).await;
+        let results = lenient.unwrap().unwrap().results;
+        assert_eq!(dec!(2.2346), results.get(&ClientId(1)).unwrap().available);
+    }
+    #[tokio::test]
+    async fn sorted_by_client_rejects_reappearing_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = InputOptions {
+            sorted_by_client: true,
+            ..Default::default()
+        };
+        let rows = "deposit,1,1,2\nwithdrawal,1,2,1\ndeposit,2,3,5\ndispute,2,3,\n";
+        let sorted = vec![write_csv(&dir.path().join("sorted.csv"), rows)];
+        let builder = AccountingEngine::builder().sorted_by_client(true);
+        let result = process_files(&sorted, None, builder, &options).await;
+        let result = result.unwrap().unwrap().results;
+        assert_eq!(dec!(1), result.get(&ClientId(1)).unwrap().available);
+        assert_eq!(dec!(5), result.get(&ClientId(2)).unwrap().held);
+
+        let rows = "deposit,1,1,2\ndeposit,2,2,5\nwithdrawal,1,3,1\n";
+        let unsorted = vec![write_csv(&dir.path().join("unsorted.csv"), rows)];
+        let builder = AccountingEngine::builder().sorted_by_client(true);
+        let result = process_files(&unsorted, None, builder, &options).await;
+        assert_eq!(
+            "Input isn't sorted by client: client 1 reappears on line 4",
+            result.unwrap_err().to_string()
+        );
+    }
+    #[tokio::test]
+    async fn only_clients_drops_other_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,2\ndeposit,2,2,5\ndispute,2,2,\nwithdrawal,1,3,1\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let options = InputOptions {
+            clients: Some(ClientFilter::Only(BTreeSet::from([ClientId(1)]))),
+            ..Default::default()
+        };
+        let builder = AccountingEngine::builder();
+        let result = process_files(&input, None, builder, &options).await;
+        let result = result.unwrap().unwrap().results;
+        assert_eq!(vec![&ClientId(1)], result.keys().collect::<Vec<_>>());
+        assert_eq!(dec!(1), result.get(&ClientId(1)).unwrap().available);
+    }
+    #[tokio::test]
+    async fn largest_ids_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,65535,4294967295,2.5\ndispute,65535,4294967295,\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let (echo, mut echoed) = unbounded_channel();
+        let builder = AccountingEngine::builder().echo_events(echo);
+        let options = InputOptions::default();
+        let result = process_files(&input, None, builder, &options).await;
+        let output = convert_output(result.unwrap().unwrap().results, Default::default());
+        let mut written = vec![];
+        write_output(&mut written, output).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n65535,0,2.5,2.5,false\n",
+            String::from_utf8(written).unwrap()
+        );
+        let mut events = vec![];
+        while let Some((_, event)) = echoed.recv().await {
+            events.push(event);
+        }
+        let mut written = vec![];
+        write_events(&mut written, events).unwrap();
+        assert_eq!(format!("type,client,tx,amount\n{}", rows), String::from_utf8(written).unwrap());
+    }
+    #[tokio::test]
+    async fn split_output_by_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,1\ndeposit,2,2,2\ndeposit,3,3,3\ndispute,3,3,\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let result = process(&input).await;
+        let out = dir.path().join("clients");
+        crate::split_output(&out, convert_output(result, Default::default())).unwrap();
+        assert_eq!(3, std::fs::read_dir(&out).unwrap().count());
+        let header = "client,available,held,total,locked\n";
+        for (client, row) in [(1, "1,1,0,1,false"), (2, "2,2,0,2,false"), (3, "3,0,3,3,false")] {
+            let file = out.join(format!("client_{}.csv", client));
+            let content = std::fs::read_to_string(file).unwrap();
+            assert_eq!(format!("{}{}\n", header, row), content);
+        }
+    }
+    #[tokio::test]
+    async fn signed_amounts_pick_the_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "transaction,1,1,+50\ntransaction,1,2,-20\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let options = InputOptions {
+            signed_amounts: true,
+            ..Default::default()
+        };
+        let result = process_files(&input, None, AccountingEngine::builder(), &options)
+            .await
+            .unwrap()
+            .unwrap()
+            .results;
+        assert_eq!(dec!(30), result.get(&ClientId(1)).unwrap().available);
+        // Without the mode `transaction` rows are unknown
+        assert!(process(&input).await.is_empty());
+    }
+    /// Accepts `capacity` bytes and fails every write after that
+    struct FullDisk {
+        capacity: usize,
+    }
+    impl std::io::Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.capacity < buf.len() {
+                return Err(std::io::Error::other("No space left on device"));
+            }
+            self.capacity -= buf.len();
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    #[tokio::test]
+    async fn write_error_is_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows: String = (0..1000)
+            .map(|client| format!("deposit,{},{},1\n", client, client))
+            .collect();
+        let input = vec![write_csv(&dir.path().join("tx.csv"), &rows)];
+        let output = convert_output(process(&input).await, Default::default());
+        let error = write_output(FullDisk { capacity: 100 }, output).unwrap_err();
+        assert!(error.to_string().contains("No space left on device"));
+    }
+    #[test]
+    fn malformed_rows_are_rejected() {
+        let input = |tx_type, amount| Input {
+            tx_type,
+            client: ClientId(1),
+            tx: TxId(1),
+            amount,
+            currency: None,
+            timestamp: None,
+        };
+        let options = InputOptions::default();
+        let convert = |entry| convert_input(entry, Some(2), &options);
+        let in_currency = Input {
+            currency: Some("EURO".to_string()),
+            ..input(TransactionType::Deposit, Some(dec!(1)))
+        };
+        assert!(matches!(convert(in_currency), Err(InputError::InvalidCurrency(_))));
+        assert!(matches!(
+            convert(input(TransactionType::Transaction, Some(dec!(1)))),
+            Err(InputError::UnknownType(tx_type)) if tx_type == "transaction"
+        ));
+        assert!(matches!(
+            convert(input(TransactionType::Withdrawal, None)),
+            Err(InputError::MissingAmount)
+        ));
+        assert!(matches!(
+            convert(input(TransactionType::FeeSweep, None)),
+            Err(InputError::MissingAmount)
+        ));
+        assert!(matches!(
+            convert(input(TransactionType::Dispute, Some(dec!(1)))),
+            Err(InputError::UnexpectedAmount)
+        ));
+        assert!(matches!(
+            convert(input(TransactionType::Freeze, Some(dec!(1)))),
+            Err(InputError::UnexpectedAmount)
+        ));
+        assert!(matches!(
+            convert(input(TransactionType::Deposit, Some(dec!(1)))),
+            Ok(Event::Deposit(Transaction { line: Some(2), .. }))
+        ));
+    }
+    #[tokio::test]
+    async fn columns_are_matched_by_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tx.csv");
+        let csv = "memo,amount,tx,client,type\n\
+                   salary,5,1,1,deposit\n\
+                   \"rent, march\",2,2,1,withdrawal\n\
+                   ,,1,1,dispute\n\
+                   bonus, 1.5 ,3,2,deposit\n";
+        std::fs::write(&path, csv).unwrap();
+        let result = process(&[path.to_str().unwrap().to_string()]).await;
+        assert_eq!(dec!(-2), result[&ClientId(1)].available);
+        assert_eq!(dec!(5), result[&ClientId(1)].held);
+        assert_eq!(dec!(1.5), result[&ClientId(2)].available);
+    }
+    #[tokio::test]
+    async fn rows_are_ordered_by_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tx.csv");
+        let csv = "type,client,tx,amount,timestamp\n\
+                   dispute,1,1,,20\n\
+                   deposit,1,1,5,10\n\
+                   deposit,2,2,1,30\n\
+                   withdrawal,2,3,1,\n\
+                   deposit,2,4,1,30\n";
+        std::fs::write(&path, csv).unwrap();
+        let input = vec![path.to_str().unwrap().to_string()];
+        let in_file_order = process(&input).await;
+        assert_eq!(dec!(5), in_file_order[&ClientId(1)].available);
+        assert_eq!(dec!(0), in_file_order[&ClientId(1)].held);
+        assert_eq!(dec!(1), in_file_order[&ClientId(2)].available);
+
+        let options = InputOptions {
+            order_by_time: true,
+            ..InputOptions::default()
+        };
+        let result = process_files(&input, None, AccountingEngine::builder(), &options).await;
+        let in_time_order = result.unwrap().unwrap().results;
+        assert_eq!(dec!(0), in_time_order[&ClientId(1)].available);
+        assert_eq!(dec!(5), in_time_order[&ClientId(1)].held);
+        assert_eq!(dec!(1), in_time_order[&ClientId(2)].available);
+    }
+    #[test]
+    fn types_in_any_case() {
+        let options = InputOptions::default();
+        let convert = |tx_type: &str| {
+            let row = format!(
+                r#"{{"type": "{}", "client": 1, "tx": 2, "amount": "3"}}"#,
+                tx_type
+            );
+            let entry: Result<Input, _> = serde_json::from_str(&row);
+            entry.map(|entry| convert_input(entry, None, &options).unwrap())
+        };
+        let deposit = Event::deposit(TxId(2), ClientId(1), dec!(3));
+        for tx_type in ["deposit", "Deposit", "DEPOSIT", " deposit ", "  dEpOsIt"] {
+            assert_eq!(deposit, convert(tx_type).unwrap());
+        }
+        assert!(convert("DEP OSIT").is_err());
+        assert!(convert("deposits").is_err());
+    }
+    #[tokio::test]
+    async fn malformed_rows_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows = "deposit,1,1,5\ndepositt,1,2,5\ndispute,1,1,5\nwithdrawal,1,3,\n";
+        let input = vec![write_csv(&dir.path().join("tx.csv"), rows)];
+        let result = process(&input).await;
+        assert_eq!(dec!(5), result[&ClientId(1)].available);
+        assert_eq!(dec!(0), result[&ClientId(1)].held);
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_input_is_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let rows: String = (0..200_000)
+            .map(|tx| match tx / 100 % 4 {
+                3 => format!("withdrawal,{},{},1\n", tx % 100, tx),
+                _ => format!("deposit,{},{},1\n", tx % 100, tx),
+            })
+            .collect();
+        let input = vec![write_csv(&dir.path().join("tx.csv"), &rows)];
+        let result = process(&input).await;
+        assert_eq!(100, result.len());
+        for account in result.values() {
+            assert_eq!(dec!(1000), account.available);
+        }
+    }
+    #[tokio::test]
+    async fn json_input_matches_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = write_csv(
+            &dir.path().join("tx.csv"),
+            "deposit,1,1,2.5\nwithdrawal,1,2,1\ndeposit,2,3,4\ndispute,2,3,\n",
+        );
+        let records = [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}"#,
+            r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "1"}"#,
+            r#"{"type": "deposit", "client": 2, "tx": 3, "amount": "4"}"#,
+            r#"{"type": "dispute", "client": 2, "tx": 3}"#,
+        ];
+        let array = dir.path().join("tx.json");
+        std::fs::write(&array, format!("[{}]", records.join(",\n"))).unwrap();
+        let lines = dir.path().join("tx.ndjson");
+        std::fs::write(&lines, records.join("\n")).unwrap();
+
+        let output = |path: String, format| async move {
+            let options = InputOptions {
+                format,
+                ..Default::default()
+            };
+            let builder = AccountingEngine::builder();
+            let result = process_files(&[path], None, builder, &options).await;
+            let mut written = vec![];
+            let output = convert_output(result.unwrap().unwrap().results, Default::default());
+            write_output(&mut written, output).unwrap();
+            written
+        };
+        let expected = output(csv, InputFormat::Csv).await;
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,0,4,4,false\n",
+            String::from_utf8_lossy(&expected)
+        );
+        let array = array.to_str().unwrap().to_string();
+        assert_eq!(expected, output(array, InputFormat::Json).await);
+        let lines = lines.to_str().unwrap().to_string();
+        assert_eq!(expected, output(lines, InputFormat::Json).await);
+    }
 }
@@ -0,0 +1,112 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for monitoring that are shared by all accounts of an engine
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub deposits: AtomicU64,
+    /// Withdrawals rejected because the account couldn't afford them
+    pub insufficient_funds: AtomicU64,
+    pub disputes: AtomicU64,
+    pub chargebacks: AtomicU64,
+    /// Accounts that were frozen, by a chargeback or otherwise
+    pub frozen_accounts: AtomicU64,
+}
+impl Metrics {
+    /// Renders the counters in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let counters = [
+            (
+                "accounting_deposits_total",
+                "Deposits processed",
+                &self.deposits,
+            ),
+            (
+                "accounting_insufficient_funds_total",
+                "Withdrawals rejected for insufficient funds",
+                &self.insufficient_funds,
+            ),
+            (
+                "accounting_disputes_total",
+                "Disputes opened",
+                &self.disputes,
+            ),
+            (
+                "accounting_chargebacks_total",
+                "Chargebacks applied",
+                &self.chargebacks,
+            ),
+            (
+                "accounting_frozen_accounts_total",
+                "Accounts frozen",
+                &self.frozen_accounts,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, counter) in counters {
+            let value = counter.load(Ordering::SeqCst);
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} counter", name).unwrap();
+            writeln!(text, "{} {}", name, value).unwrap();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::engine::{AccountingEngine, ClientId, TxId};
+    use crate::metrics::Metrics;
+    use crate::transactions::Event;
+    use rust_decimal_macros::dec;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn metrics_of_a_scenario() {
+        let metrics = Arc::new(Metrics::default());
+        let (engine, sender) = AccountingEngine::builder().metrics(metrics.clone()).build();
+        let events = vec![
+            Event::deposit(TxId(1), ClientId(1), dec!(10)),
+            Event::withdrawal(TxId(2), ClientId(1), dec!(20)),
+            Event::Dispute {
+                client: ClientId(1),
+                tx_id: TxId(1),
+            },
+            Event::Chargeback {
+                client: ClientId(1),
+                tx_id: TxId(1),
+            },
+            Event::deposit(TxId(3), ClientId(2), dec!(5)),
+            Event::Dispute {
+                client: ClientId(2),
+                tx_id: TxId(3),
+            },
+            Event::Resolve {
+                client: ClientId(2),
+                tx_id: TxId(3),
+            },
+            Event::withdrawal(TxId(4), ClientId(2), dec!(6)),
+            Event::Freeze {
+                client: ClientId(3),
+            },
+        ];
+        for event in events {
+            sender.send(event).await.unwrap();
+        }
+        drop(sender);
+        engine.process_txs().await.unwrap();
+        assert_eq!(2, metrics.deposits.load(Ordering::SeqCst));
+        assert_eq!(2, metrics.insufficient_funds.load(Ordering::SeqCst));
+        assert_eq!(2, metrics.disputes.load(Ordering::SeqCst));
+        assert_eq!(1, metrics.chargebacks.load(Ordering::SeqCst));
+        assert_eq!(2, metrics.frozen_accounts.load(Ordering::SeqCst));
+        let text = metrics.render();
+        assert!(text.contains(
+            "# HELP accounting_deposits_total Deposits processed\n\
+             # TYPE accounting_deposits_total counter\n\
+             accounting_deposits_total 2\n"
+        ));
+        assert!(text.ends_with("accounting_frozen_accounts_total 2\n"));
+    }
+}
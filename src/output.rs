@@ -0,0 +1,491 @@
+use crate::config::Rounding;
+use crate::engine::{AccountingResult, Amount, ClientId};
+use rust_decimal::RoundingStrategy;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// `INSERT` statements into `table`
+    Sql { table: String },
+    /// One json object per client and line
+    Ndjson,
+    /// A json array of one object per client
+    Json,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Output {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<Amount>,
+    locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disputed_count: Option<usize>,
+}
+/// How the results are turned into output rows
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputOptions {
+    /// Write the `pending` column
+    pub pending: bool,
+    /// Write the `total` column
+    pub total: bool,
+    /// Write the `disputed_count` column
+    pub disputed_count: bool,
+    /// Cut amounts off after this many decimal places instead of writing them in full
+    pub truncate: Option<u32>,
+    /// Round amounts and pad them with zeros to exactly the scale of the rounding.
+    /// Takes precedence over `truncate`.
+    pub scale: Option<Rounding>,
+}
+impl OutputOptions {
+    /// The csv columns of the rows converted with these options
+    pub fn columns(&self) -> Vec<&'static str> {
+        let mut columns = vec!["client", "available", "held"];
+        if self.pending {
+            columns.push("pending");
+        }
+        if self.total {
+            columns.push("total");
+        }
+        columns.push("locked");
+        if self.disputed_count {
+            columns.push("disputed_count");
+        }
+        columns
+    }
+}
+/// The number of decimal places of [`OutputOptions::scale`] unless another one is chosen
+pub const DEFAULT_OUTPUT_SCALE: u32 = 4;
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            pending: false,
+            total: true,
+            disputed_count: false,
+            truncate: None,
+            scale: None,
+        }
+    }
+}
+
+pub fn convert_output(
+    result: BTreeMap<ClientId, AccountingResult>,
+    options: OutputOptions,
+) -> Vec<Output> {
+    convert_output_in_order(result, &[], options)
+}
+/// Like [`convert_output`], but the clients in `order` come first and in that order.
+/// The remaining clients follow sorted by id.
+pub fn convert_output_in_order(
+    mut result: BTreeMap<ClientId, AccountingResult>,
+    order: &[ClientId],
+    options: OutputOptions,
+) -> Vec<Output> {
+    let amount = |amount: Amount| match (options.scale, options.truncate) {
+        (Some(rounding), _) => {
+            let mut rounded = rounding.round(amount);
+            // Rounding a small negative amount leaves a zero that would be written as -0
+            if rounded.is_zero() {
+                rounded.set_sign_positive(true);
+            }
+            rounded.rescale(rounding.scale);
+            rounded
+        }
+        (None, Some(scale)) => amount
+            .round_dp_with_strategy(scale, RoundingStrategy::ToZero)
+            .normalize(),
+        (None, None) => amount.normalize(),
+    };
+    let mut output = vec![];
+    let ordered: Vec<_> = order
+        .iter()
+        .filter_map(|client| Some((*client, result.remove(client)?)))
+        .collect();
+    for (client, entry) in ordered.into_iter().chain(result) {
+        output.push(Output {
+            client,
+            available: amount(entry.available),
+            held: amount(entry.held),
+            pending: options.pending.then(|| amount(entry.pending)),
+            total: options.total.then(|| amount(entry.total)),
+            locked: entry.locked,
+            disputed_count: options.disputed_count.then_some(entry.disputed_count),
+        })
+    }
+    output
+}
+/// Writes the output in `format`. A csv without any clients still gets the header
+/// of the columns chosen by `options`.
+pub fn write_results<W: Write>(
+    writer: W,
+    format: &OutputFormat,
+    output: Vec<Output>,
+    options: OutputOptions,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Csv if output.is_empty() => write_header(writer, options)?,
+        OutputFormat::Csv => write_output(writer, output)?,
+        OutputFormat::Sql { table } => write_sql(writer, table, output)?,
+        OutputFormat::Ndjson => write_ndjson(writer, output)?,
+        OutputFormat::Json => write_json(writer, output)?,
+    }
+    Ok(())
+}
+pub fn write_output<W: Write>(writer: W, output: Vec<Output>) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    for entry in output {
+        wtr.serialize(entry)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+/// Writes only the header row of a csv output
+pub fn write_header<W: Write>(writer: W, options: OutputOptions) -> csv::Result<()> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(options.columns())?;
+    wtr.flush()?;
+    Ok(())
+}
+/// Writes the result of every client into its own `<dir>/client_<id>.csv`
+pub fn split_output(dir: &Path, output: Vec<Output>) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+    for entry in output {
+        let file = std::fs::File::create(dir.join(format!("client_{}.csv", entry.client)))?;
+        write_output(file, vec![entry])?;
+    }
+    Ok(())
+}
+/// Writes one json object per client and line
+pub fn write_ndjson<W: Write>(mut writer: W, output: Vec<Output>) -> std::io::Result<()> {
+    for entry in output {
+        serde_json::to_writer(&mut writer, &entry)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+/// Writes a json array of all clients. Amounts are strings to keep their precision.
+pub fn write_json<W: Write>(mut writer: W, output: Vec<Output>) -> std::io::Result<()> {
+    serde_json::to_writer(&mut writer, &output)?;
+    writeln!(writer)?;
+    writer.flush()
+}
+/// Writes one `INSERT` statement per client into `table`
+pub fn write_sql<W: Write>(mut writer: W, table: &str, output: Vec<Output>) -> std::io::Result<()> {
+    let table = quote_identifier(table);
+    for entry in output {
+        let mut columns = vec!["client", "available", "held"];
+        let mut values = vec![
+            entry.client.to_string(),
+            entry.available.to_string(),
+            entry.held.to_string(),
+        ];
+        if let Some(pending) = entry.pending {
+            columns.push("pending");
+            values.push(pending.to_string());
+        }
+        if let Some(total) = entry.total {
+            columns.push("total");
+            values.push(total.to_string());
+        }
+        columns.push("locked");
+        values.push(if entry.locked { "TRUE" } else { "FALSE" }.to_string());
+        if let Some(disputed_count) = entry.disputed_count {
+            columns.push("disputed_count");
+            values.push(disputed_count.to_string());
+        }
+        writeln!(
+            writer,
+            "INSERT INTO {} ({}) VALUES ({});",
+            table,
+            columns.join(", "),
+            values.join(", ")
+        )?;
+    }
+    writer.flush()
+}
+/// Quotes `name` unless it is a plain (optionally schema qualified) identifier
+fn quote_identifier(name: &str) -> String {
+    let plain = |part: &str| {
+        let mut chars = part.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if name.split('.').all(plain) {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Rounding;
+    use crate::engine::{AccountingResult, ClientId};
+    use crate::output::{
+        convert_output, convert_output_in_order, write_json, write_ndjson, write_output, write_sql,
+        Output, OutputOptions, DEFAULT_OUTPUT_SCALE,
+    };
+    use rust_decimal::{Decimal, RoundingStrategy};
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+
+    fn sql(table: &str, result: BTreeMap<ClientId, AccountingResult>) -> String {
+        let mut written = vec![];
+        write_sql(
+            &mut written,
+            table,
+            convert_output(result, OutputOptions::default()),
+        )
+        .unwrap();
+        String::from_utf8(written).unwrap()
+    }
+
+    #[test]
+    fn sql_inserts_for_two_clients() {
+        let result = BTreeMap::from([
+            (
+                ClientId(1),
+                AccountingResult {
+                    available: dec!(1.5000),
+                    total: dec!(1.5000),
+                    external: dec!(1.5000),
+                    ..Default::default()
+                },
+            ),
+            (
+                ClientId(2),
+                AccountingResult {
+                    available: dec!(-0.25),
+                    held: dec!(10.1234),
+                    total: dec!(9.8734),
+                    locked: true,
+                    external: dec!(9.8734),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        assert_eq!(
+            "INSERT INTO accounts (client, available, held, total, locked) \
+             VALUES (1, 1.5, 0, 1.5, FALSE);\n\
+             INSERT INTO accounts (client, available, held, total, locked) \
+             VALUES (2, -0.25, 10.1234, 9.8734, TRUE);\n",
+            sql("accounts", result)
+        );
+    }
+    #[test]
+    fn sql_quotes_unusual_table_names() {
+        let result = BTreeMap::from([(
+            ClientId(1),
+            AccountingResult {
+                available: dec!(1),
+                total: dec!(1),
+                external: dec!(1),
+                ..Default::default()
+            },
+        )]);
+        assert!(sql("reporting.accounts", result.clone())
+            .starts_with("INSERT INTO reporting.accounts ("));
+        assert!(sql("my \"accounts\"", result).starts_with("INSERT INTO \"my \"\"accounts\"\"\" ("));
+    }
+    #[test]
+    fn total_column_can_be_left_out() {
+        let result = BTreeMap::from([(
+            ClientId(1),
+            AccountingResult {
+                available: dec!(1),
+                held: dec!(2),
+                total: dec!(3),
+                external: dec!(3),
+                ..Default::default()
+            },
+        )]);
+        let csv = |options| {
+            let mut written = vec![];
+            write_output(&mut written, convert_output(result.clone(), options)).unwrap();
+            String::from_utf8(written).unwrap()
+        };
+        assert_eq!(
+            "client,available,held,total,locked\n1,1,2,3,false\n",
+            csv(OutputOptions::default())
+        );
+        let options = OutputOptions {
+            total: false,
+            ..Default::default()
+        };
+        assert_eq!("client,available,held,locked\n1,1,2,false\n", csv(options));
+    }
+    #[test]
+    fn truncate_amounts() {
+        let result = BTreeMap::from([(
+            ClientId(1),
+            AccountingResult {
+                available: dec!(1.99999),
+                held: dec!(0.00005),
+                total: dec!(2.00004),
+                external: dec!(2.00004),
+                ..Default::default()
+            },
+        )]);
+        let options = OutputOptions {
+            truncate: Some(4),
+            ..Default::default()
+        };
+        let mut written = vec![];
+        write_output(&mut written, convert_output(result, options)).unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.9999,0,2,false\n",
+            String::from_utf8(written).unwrap()
+        );
+    }
+    #[test]
+    fn fixed_scale_rounding() {
+        let result = BTreeMap::from([(
+            ClientId(1),
+            AccountingResult {
+                available: dec!(1.00005),
+                held: dec!(2),
+                total: dec!(3.00005),
+                external: dec!(3.00005),
+                ..Default::default()
+            },
+        )]);
+        let csv = |strategy| {
+            let options = OutputOptions {
+                scale: Some(Rounding {
+                    scale: DEFAULT_OUTPUT_SCALE,
+                    strategy,
+                }),
+                ..Default::default()
+            };
+            let mut written = vec![];
+            write_output(&mut written, convert_output(result.clone(), options)).unwrap();
+            String::from_utf8(written).unwrap()
+        };
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.0000,2.0000,3.0000,false\n",
+            csv(RoundingStrategy::MidpointNearestEven)
+        );
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.0001,2.0000,3.0001,false\n",
+            csv(RoundingStrategy::MidpointAwayFromZero)
+        );
+    }
+    #[test]
+    fn ndjson_line_per_client() {
+        let result = BTreeMap::from([
+            (
+                ClientId(1),
+                AccountingResult {
+                    available: dec!(1.5),
+                    total: dec!(1.5),
+                    external: dec!(1.5),
+                    ..Default::default()
+                },
+            ),
+            (
+                ClientId(2),
+                AccountingResult {
+                    held: dec!(2),
+                    total: dec!(2),
+                    locked: true,
+                    external: dec!(2),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let mut written = vec![];
+        write_ndjson(
+            &mut written,
+            convert_output(result, OutputOptions::default()),
+        )
+        .unwrap();
+        let written = String::from_utf8(written).unwrap();
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(
+            r#"{"client":1,"available":"1.5","held":"0","total":"1.5","locked":false}"#,
+            lines[0]
+        );
+        for (line, client) in lines.iter().zip([1, 2]) {
+            let object: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(client, object["client"]);
+        }
+        assert_eq!(2, lines.len());
+    }
+    #[test]
+    fn json_and_csv_of_the_same_result() {
+        let result = BTreeMap::from([
+            (
+                ClientId(1),
+                AccountingResult {
+                    available: dec!(1.5),
+                    held: dec!(0.0001),
+                    total: dec!(1.5001),
+                    external: dec!(1.5001),
+                    ..Default::default()
+                },
+            ),
+            (
+                ClientId(2),
+                AccountingResult {
+                    locked: true,
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let mut csv = vec![];
+        write_output(
+            &mut csv,
+            convert_output(result.clone(), OutputOptions::default()),
+        )
+        .unwrap();
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5,0.0001,1.5001,false\n2,0,0,0,true\n",
+            String::from_utf8(csv).unwrap()
+        );
+        let mut json = vec![];
+        write_json(&mut json, convert_output(result, OutputOptions::default())).unwrap();
+        assert_eq!(
+            concat!(
+                r#"[{"client":1,"available":"1.5","held":"0.0001","#,
+                r#""total":"1.5001","locked":false},"#,
+                r#"{"client":2,"available":"0","held":"0","total":"0","locked":true}]"#,
+                "\n"
+            ),
+            String::from_utf8(json).unwrap()
+        );
+    }
+    #[test]
+    fn rows_in_first_seen_order() {
+        let result: BTreeMap<_, _> = (1..=4)
+            .map(|client| {
+                let amount = Decimal::from(client);
+                let result = AccountingResult {
+                    available: amount,
+                    total: amount,
+                    external: amount,
+                    ..Default::default()
+                };
+                (ClientId(client), result)
+            })
+            .collect();
+        let clients =
+            |output: Vec<Output>| -> Vec<_> { output.iter().map(|row| row.client.0).collect() };
+        let sorted = convert_output(result.clone(), OutputOptions::default());
+        assert_eq!(vec![1, 2, 3, 4], clients(sorted));
+        let first_seen = convert_output_in_order(
+            result,
+            &[ClientId(3), ClientId(1), ClientId(4)],
+            OutputOptions::default(),
+        );
+        assert_eq!(vec![3, 1, 4, 2], clients(first_seen));
+    }
+}
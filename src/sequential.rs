@@ -0,0 +1,142 @@
+//! The single threaded counterpart of [`AccountingEngine`](crate::engine::AccountingEngine)
+use crate::account::Account;
+use crate::config::EngineConfig;
+use crate::engine::{finish_results, Amount, ClientId, EngineOutput, ReconciliationReport};
+use crate::errors::{log_rejection, AccountingError};
+use crate::transactions::{Event, LifecycleEvent};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::channel;
+use tokio::sync::RwLock;
+use tracing::Instrument;
+
+/// Applies `events` in order to accounts that are owned by the caller instead of running in
+/// their own tasks. Routes the events like [`AccountingEngine`](crate::engine::AccountingEngine)
+/// does, so both produce the same output.
+pub async fn process(
+    config: EngineConfig,
+    events: impl IntoIterator<Item = Event>,
+) -> EngineOutput {
+    let config = Arc::new(config);
+    let transactions = Arc::new(RwLock::new(BTreeMap::new()));
+    let suspense_held = Arc::new(Mutex::new(Amount::ZERO));
+    let mut accounts: BTreeMap<ClientId, Account> = BTreeMap::new();
+    let mut finished = BTreeMap::new();
+    let mut rejections = vec![];
+    let mut report = ReconciliationReport::default();
+    let mut sequence = 0;
+    for event in events {
+        if let Event::Query { client, reply } = &event {
+            let account = accounts.get(client).map(Account::result);
+            reply.send(account.or_else(|| finished.get(client).cloned()));
+            continue;
+        }
+        config.count_received();
+        let client = match event.client() {
+            Some(client) => client,
+            None => {
+                for account in accounts.values_mut() {
+                    let span = account.span();
+                    account
+                        .apply(sequence, event.clone())
+                        .instrument(span)
+                        .await;
+                }
+                sequence += 1;
+                continue;
+            }
+        };
+        if config.sorted_by_client {
+            if finished.contains_key(&client) {
+                let error = AccountingError::ClientNotContiguous(client);
+                reject(&config, &mut rejections, sequence, event, error);
+                continue;
+            }
+            let others: Vec<_> = accounts
+                .keys()
+                .filter(|id| **id != client)
+                .copied()
+                .collect();
+            for other in others {
+                let mut account = accounts.remove(&other).unwrap();
+                account.span().in_scope(|| account.close());
+                rejections.append(&mut account.rejections);
+                report.add(&account);
+                finished.insert(other, account.result());
+            }
+        }
+        let open = |accounts: &mut BTreeMap<ClientId, Account>, client| {
+            accounts.entry(client).or_insert_with(|| {
+                // The account is driven by `apply` instead of its channel
+                let (_, receiver) = channel(1);
+                let account = Account::new(
+                    client,
+                    receiver,
+                    transactions.clone(),
+                    config.clone(),
+                    suspense_held.clone(),
+                );
+                if let Some(first_seen) = &config.first_seen {
+                    first_seen.lock().unwrap().push(client);
+                }
+                account.emit(LifecycleEvent::AccountCreated(client));
+                account
+            });
+        };
+        open(&mut accounts, client);
+        if let Event::Transfer {
+            to, tx_id, amount, ..
+        } = event
+        {
+            if to == client {
+                let error = AccountingError::SelfTransfer(client);
+                reject(&config, &mut rejections, sequence, event, error);
+                continue;
+            }
+            if config.sorted_by_client {
+                let error = AccountingError::ClientNotContiguous(to);
+                reject(&config, &mut rejections, sequence, event, error);
+                continue;
+            }
+            // Like the engine, only opens a new target once the funds were debited
+            let credit = accounts
+                .get(&to)
+                .map_or(Ok(()), |target| target.check_credit(amount));
+            if let Err(error) = credit {
+                reject(&config, &mut rejections, sequence, event, error);
+                continue;
+            }
+            let source = accounts.get_mut(&client).unwrap();
+            let span = source.span();
+            if source.apply(sequence, event).instrument(span).await {
+                open(&mut accounts, to);
+                accounts.get_mut(&to).unwrap().credit(tx_id, amount);
+            }
+        } else {
+            let account = accounts.get_mut(&client).unwrap();
+            let span = account.span();
+            account.apply(sequence, event).instrument(span).await;
+        }
+        sequence += 1;
+    }
+    for (client, mut account) in accounts {
+        account.span().in_scope(|| account.close());
+        rejections.append(&mut account.rejections);
+        report.add(&account);
+        finished.insert(client, account.result());
+    }
+    finish_results(&config, &suspense_held, &mut finished);
+    EngineOutput::new(finished, rejections, report)
+}
+/// Records a rejection that happened before the event reached an account
+fn reject(
+    config: &EngineConfig,
+    rejections: &mut Vec<(u64, Event, AccountingError)>,
+    sequence: u64,
+    event: Event,
+    error: AccountingError,
+) {
+    config.count_rejected();
+    log_rejection(&event, &error);
+    rejections.push((sequence, event, error));
+}
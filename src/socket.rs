@@ -0,0 +1,135 @@
+use accounting_engine::engine::AccountingEngineBuilder;
+use accounting_engine::output::{convert_output, write_results, OutputFormat, OutputOptions};
+use accounting_engine::transactions::Event;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Serves every connection to `listener` in its own task with its own engine.
+/// A client writes one json event per line, in the format of
+/// [`accounting_engine::transactions::write_jsonl`], and shuts down its writing half,
+/// then it receives the resulting balances in `format`.
+pub async fn serve(
+    listener: UnixListener,
+    builder: impl Fn() -> AccountingEngineBuilder,
+    format: OutputFormat,
+    output_options: OutputOptions,
+) -> std::io::Result<()> {
+    let format = Arc::new(format);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (builder, format) = (builder(), format.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, builder, &format, output_options).await {
+                tracing::error!(error = %e, "connection failed");
+            }
+        });
+    }
+}
+/// Feeds the events of `stream` to an engine as they arrive. A line that isn't an event
+/// ends the input, and the client gets the error instead of the balances.
+async fn handle(
+    stream: UnixStream,
+    builder: AccountingEngineBuilder,
+    format: &OutputFormat,
+    output_options: OutputOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let (engine, sender) = builder.build();
+    let processing = tokio::spawn(engine.process_txs());
+    let mut lines = BufReader::new(reader).lines();
+    let mut fed = Ok(());
+    let mut number = 0;
+    while let Some(line) = lines.next_line().await? {
+        number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Event>(&line) {
+            Ok(event) => sender.send(event).await?,
+            Err(e) => {
+                fed = Err(format!("line {}: {}", number, e));
+                break;
+            }
+        }
+    }
+    drop(sender);
+    let result = processing.await??.results;
+    let mut written = vec![];
+    match fed {
+        Ok(()) => {
+            let output = convert_output(result, output_options);
+            write_results(&mut written, format, output, output_options)?
+        }
+        Err(e) => written = format!("{}\n", e).into_bytes(),
+    }
+    writer.write_all(&written).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::socket::serve;
+    use accounting_engine::engine::AccountingEngine;
+    use accounting_engine::output::OutputFormat;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{UnixListener, UnixStream};
+
+    async fn request(stream: &mut UnixStream, events: &str) -> String {
+        stream.write_all(events.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn results_are_sent_back_on_eof() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("acct.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = serve(
+            listener,
+            AccountingEngine::builder,
+            OutputFormat::Csv,
+            Default::default(),
+        );
+        let client = async {
+            // A client that keeps its connection open doesn't hold up the others
+            let idle = UnixStream::connect(&path).await.unwrap();
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            let first = request(
+                &mut stream,
+                concat!(
+                    r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#,
+                    "\n",
+                    r#"{"type":"withdrawal","client":1,"tx":2,"amount":"1"}"#,
+                    "\n\n",
+                    r#"{"type":"deposit","client":2,"tx":3,"amount":"1"}"#,
+                    "\n",
+                ),
+            )
+            .await;
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            let events = concat!(
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"7"}"#,
+                "\n",
+                "deposit,1,2,1\n",
+            );
+            let second = request(&mut stream, events).await;
+            drop(idle);
+            (first, second)
+        };
+        let (first, second) = tokio::select! {
+            result = server => panic!("server stopped: {:?}", result),
+            responses = client => responses,
+        };
+        assert_eq!(
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n2,1,0,1,false\n",
+            first
+        );
+        assert!(second.starts_with("line 2: "), "{}", second);
+    }
+}
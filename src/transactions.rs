@@ -1,18 +1,454 @@
-use crate::engine::{Amount, ClientId, TxId};
+use crate::engine::{AccountingResult, Amount, ClientId, TxId};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
 
-#[derive(Clone, Debug)]
+/// Serializes as an object tagged with the `type` of its row in the input.
+/// Deposits, withdrawals, disputes, resolves, chargebacks and partial disputes
+/// are read back by the json input like their rows.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Event {
     Deposit(Transaction),
     Withdrawal(Transaction),
-    Dispute { client: ClientId, tx_id: TxId },
-    Resolve { client: ClientId, tx_id: TxId },
-    Chargeback { client: ClientId, tx_id: TxId },
+    Dispute {
+        client: ClientId,
+        #[serde(rename = "tx")]
+        tx_id: TxId,
+    },
+    Resolve {
+        client: ClientId,
+        #[serde(rename = "tx")]
+        tx_id: TxId,
+    },
+    Chargeback {
+        client: ClientId,
+        #[serde(rename = "tx")]
+        tx_id: TxId,
+    },
+    /// Disputes only `amount` of the transaction. Further partial disputes add to it
+    /// until the whole transaction is disputed.
+    PartialDispute {
+        client: ClientId,
+        #[serde(rename = "tx")]
+        tx_id: TxId,
+        amount: Amount,
+    },
+    /// Cancels `tx_id` by recording the compensating transaction `new_tx_id`
+    /// with the negated amount, so both stay in the history.
+    /// Unlike a chargeback it leaves the account unlocked. A reversed deposit has to be
+    /// available still, and a reversed transaction can't be reversed or disputed again.
+    Reverse {
+        client: ClientId,
+        #[serde(rename = "tx")]
+        tx_id: TxId,
+        new_tx_id: TxId,
+    },
+    /// Moves `amount` from the available funds of `from` to those of `to`.
+    /// Neither account changes if one of them can't take part.
+    Transfer {
+        from: ClientId,
+        to: ClientId,
+        #[serde(rename = "tx")]
+        tx_id: TxId,
+        amount: Amount,
+    },
+    /// Locks the account by an administrator
+    Freeze { client: ClientId },
+    /// Lifts a [`Event::Freeze`]. Accounts frozen by a chargeback stay frozen.
+    Unfreeze { client: ClientId },
+    /// Lifts any freeze, including one caused by a chargeback
+    Reinstate { client: ClientId },
+    /// Debits the fee `amount` from the available funds of every open account
+    FeeSweep { amount: Amount },
+    /// Asks for the balance of the client after the events before it, see
+    /// [`query_account`](crate::engine::query_account). Doesn't change the account.
+    /// It can't be serialized.
+    #[serde(skip)]
+    Query { client: ClientId, reply: QueryReply },
+}
+impl Event {
+    /// The client whose account the event belongs to. `None` for events for all accounts.
+    /// For a [`Event::Transfer`] it's the client the funds come from.
+    pub fn client(&self) -> Option<ClientId> {
+        match self {
+            Event::Deposit(tx) | Event::Withdrawal(tx) => Some(tx.client),
+            Event::Dispute { client, .. }
+            | Event::Resolve { client, .. }
+            | Event::Chargeback { client, .. }
+            | Event::PartialDispute { client, .. }
+            | Event::Reverse { client, .. }
+            | Event::Freeze { client }
+            | Event::Unfreeze { client }
+            | Event::Reinstate { client }
+            | Event::Query { client, .. } => Some(*client),
+            Event::Transfer { from, .. } => Some(*from),
+            Event::FeeSweep { .. } => None,
+        }
+    }
+    /// The transaction the event creates or refers to. `None` for administrative events.
+    pub fn tx_id(&self) -> Option<TxId> {
+        match self {
+            Event::Deposit(tx) | Event::Withdrawal(tx) => Some(tx.id),
+            Event::Dispute { tx_id, .. }
+            | Event::Resolve { tx_id, .. }
+            | Event::Chargeback { tx_id, .. }
+            | Event::PartialDispute { tx_id, .. }
+            | Event::Reverse { tx_id, .. }
+            | Event::Transfer { tx_id, .. } => Some(*tx_id),
+            Event::Freeze { .. }
+            | Event::Unfreeze { .. }
+            | Event::Reinstate { .. }
+            | Event::FeeSweep { .. }
+            | Event::Query { .. } => None,
+        }
+    }
+    pub fn transaction_type(&self) -> TransactionType {
+        match self {
+            Event::Deposit(_) => TransactionType::Deposit,
+            Event::Withdrawal(_) => TransactionType::Withdrawal,
+            Event::Dispute { .. } => TransactionType::Dispute,
+            Event::Resolve { .. } => TransactionType::Resolve,
+            Event::Chargeback { .. } => TransactionType::Chargeback,
+            Event::PartialDispute { .. } => TransactionType::PartialDispute,
+            Event::Reverse { .. } => TransactionType::Reverse,
+            Event::Transfer { .. } => TransactionType::Transfer,
+            Event::Freeze { .. } => TransactionType::Freeze,
+            Event::Unfreeze { .. } => TransactionType::Unfreeze,
+            Event::Reinstate { .. } => TransactionType::Reinstate,
+            Event::FeeSweep { .. } => TransactionType::FeeSweep,
+            Event::Query { .. } => TransactionType::Query,
+        }
+    }
+    /// The name of the event's type in the csv input
+    pub fn kind(&self) -> &'static str {
+        self.transaction_type().as_str()
+    }
+    pub fn deposit(id: TxId, client: ClientId, amount: Amount) -> Self {
+        Event::Deposit(Transaction::new(id, client, amount))
+    }
+    pub fn withdrawal(id: TxId, client: ClientId, amount: Amount) -> Self {
+        Event::Withdrawal(Transaction::new(id, client, amount))
+    }
+    /// A [`Event::Query`] for the balance of `client` and the receiver of its answer,
+    /// which is `None` if the client has no account
+    pub fn query(client: ClientId) -> (Self, oneshot::Receiver<Option<AccountingResult>>) {
+        let (reply, receiver) = oneshot::channel();
+        let reply = QueryReply(Arc::new(Mutex::new(Some(reply))));
+        (Event::Query { client, reply }, receiver)
+    }
+}
+
+/// Writes the events as json, one object per line
+pub fn write_jsonl<'a, W: Write>(
+    mut writer: W,
+    events: impl IntoIterator<Item = &'a Event>,
+) -> std::io::Result<()> {
+    for event in events {
+        serde_json::to_writer(&mut writer, event)?;
+        writeln!(writer)?;
+    }
+    writer.flush()
 }
 
+/// Where the answer to a [`Event::Query`] goes. Clones answer the same query,
+/// and only the first answer is sent.
 #[derive(Clone, Debug)]
+pub struct QueryReply(Arc<Mutex<Option<oneshot::Sender<Option<AccountingResult>>>>>);
+impl QueryReply {
+    pub fn send(&self, result: Option<AccountingResult>) {
+        if let Some(reply) = self.0.lock().unwrap().take() {
+            // The asker may have stopped waiting
+            let _ = reply.send(result);
+        }
+    }
+}
+impl PartialEq for QueryReply {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Transaction {
+    #[serde(rename = "tx", alias = "id")]
     pub id: TxId,
     pub client: ClientId,
+    /// The magnitude of the transaction, whichever direction the funds moved
     pub amount: Amount,
-    pub is_locked: bool,
+    #[serde(default)]
+    pub state: TxState,
+    /// The csv line the transaction was read from
+    pub line: Option<u64>,
+    /// The `amount` left the account. Set by the account that records a withdrawal.
+    #[serde(default)]
+    pub is_withdrawal: bool,
+    /// The part of `amount` under dispute if only part of it was disputed
+    pub disputed: Option<Amount>,
+    /// `None` for a transaction in the currency of its account, whichever that is
+    pub currency: Option<Currency>,
+    /// When the transaction happened, in seconds since the unix epoch
+    pub timestamp: Option<u64>,
+}
+impl Transaction {
+    /// A transaction in the [`TxState::Normal`] state
+    pub fn new(id: TxId, client: ClientId, amount: Amount) -> Self {
+        Transaction {
+            id,
+            client,
+            amount,
+            state: TxState::Normal,
+            line: None,
+            is_withdrawal: false,
+            disputed: None,
+            currency: None,
+            timestamp: None,
+        }
+    }
+    /// The amount a resolve or chargeback settles
+    pub fn disputed_amount(&self) -> Amount {
+        self.disputed.unwrap_or(self.amount)
+    }
+    /// The amount with the direction it moved the account's funds in
+    pub fn signed_amount(&self) -> Amount {
+        if self.is_withdrawal {
+            -self.amount
+        } else {
+            self.amount
+        }
+    }
+}
+
+/// A three letter currency code like `EUR`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency([u8; 3]);
+impl std::str::FromStr for Currency {
+    type Err = String;
+    /// Accepts three ascii letters in any case
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        match code.as_bytes() {
+            code @ [_, _, _] if code.iter().all(u8::is_ascii_alphabetic) => {
+                Ok(Currency([code[0], code[1], code[2]].map(|c| c.to_ascii_uppercase())))
+            }
+            _ => Err(format!("{} isn't a three letter currency code", code)),
+        }
+    }
+}
+impl TryFrom<String> for Currency {
+    type Error = String;
+    fn try_from(code: String) -> Result<Self, Self::Error> {
+        code.parse()
+    }
+}
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.to_string()
+    }
+}
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Only ascii letters are accepted
+        f.write_str(std::str::from_utf8(&self.0).unwrap())
+    }
+}
+
+/// The type of an event, named like in the `type` column of the input
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    PartialDispute,
+    Reverse,
+    Transfer,
+    Freeze,
+    Unfreeze,
+    Reinstate,
+    FeeSweep,
+    Query,
+    /// A deposit or withdrawal depending on the sign of its amount
+    Transaction,
+}
+impl TransactionType {
+    pub const ALL: [TransactionType; 14] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::PartialDispute,
+        TransactionType::Reverse,
+        TransactionType::Transfer,
+        TransactionType::Freeze,
+        TransactionType::Unfreeze,
+        TransactionType::Reinstate,
+        TransactionType::FeeSweep,
+        TransactionType::Query,
+        TransactionType::Transaction,
+    ];
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::PartialDispute => "partial_dispute",
+            TransactionType::Reverse => "reverse",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Unfreeze => "unfreeze",
+            TransactionType::Reinstate => "reinstate",
+            TransactionType::FeeSweep => "fee_sweep",
+            TransactionType::Query => "query",
+            TransactionType::Transaction => "transaction",
+        }
+    }
+    /// Whether rows of the input can have this type. Reversals, transfers and queries
+    /// need more than the columns of a row, so they are only sent through the library.
+    pub fn is_input(&self) -> bool {
+        !matches!(
+            self,
+            TransactionType::Reverse | TransactionType::Transfer | TransactionType::Query
+        )
+    }
+}
+impl std::str::FromStr for TransactionType {
+    type Err = String;
+    /// Accepts the names in any case and surrounded by whitespace
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        TransactionType::ALL
+            .into_iter()
+            .find(|tx_type| tx_type.as_str().eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| format!("unknown transaction type `{}`", name))
+    }
+}
+impl TryFrom<String> for TransactionType {
+    type Error = String;
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+impl From<TransactionType> for String {
+    fn from(tx_type: TransactionType) -> Self {
+        tx_type.as_str().to_string()
+    }
+}
+impl std::fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Where a transaction stands in the dispute process
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TxState {
+    #[default]
+    Normal,
+    /// The funds of the transaction are held. With [`EngineConfig::locked_deposits`]
+    /// a deposit can also arrive in this state, which lands it in the held funds
+    /// as if it was disputed already.
+    ///
+    /// [`EngineConfig::locked_deposits`]: crate::config::EngineConfig::locked_deposits
+    Disputed,
+    /// The chargeback reversed the transaction for good
+    ChargedBack,
+}
+
+/// An event that changed an account, with the balances it left behind
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppliedEvent {
+    pub tx_id: Option<TxId>,
+    /// See [`Event::kind`]
+    pub kind: &'static str,
+    pub available: Amount,
+    pub held: Amount,
+}
+
+/// Changes in an account's lifecycle, reported separately from its balances.
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum LifecycleEvent {
+    AccountCreated(ClientId),
+    AccountFrozen(ClientId),
+    /// A frozen account was unlocked again by [`Event::Unfreeze`] or [`Event::Reinstate`]
+    AccountReinstated(ClientId),
+    /// The account's event stream ended and its final balance was produced
+    AccountClosed(ClientId),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn constructors_build_unlocked_transactions() {
+        let tx = Transaction {
+            id: TxId(1),
+            client: ClientId(2),
+            amount: dec!(3.5),
+            state: TxState::Normal,
+            line: None,
+            is_withdrawal: false,
+            disputed: None,
+            currency: None,
+            timestamp: None,
+        };
+        assert_eq!(tx, Transaction::new(TxId(1), ClientId(2), dec!(3.5)));
+        assert_eq!(Event::Deposit(tx.clone()), Event::deposit(TxId(1), ClientId(2), dec!(3.5)));
+        assert_eq!(Event::Withdrawal(tx), Event::withdrawal(TxId(1), ClientId(2), dec!(3.5)));
+    }
+    #[test]
+    fn events_round_trip_through_json() {
+        let (client, tx_id) = (ClientId(1), TxId(2));
+        let deposit = Transaction {
+            currency: Some("EUR".parse().unwrap()),
+            line: Some(3),
+            ..Transaction::new(tx_id, client, dec!(1.5))
+        };
+        let events = [
+            Event::Deposit(deposit),
+            Event::withdrawal(tx_id, client, dec!(0.25)),
+            Event::Dispute { client, tx_id },
+            Event::Resolve { client, tx_id },
+            Event::Chargeback { client, tx_id },
+            Event::PartialDispute { client, tx_id, amount: dec!(1) },
+            Event::Reverse { client, tx_id, new_tx_id: TxId(3) },
+            Event::Transfer { from: client, to: ClientId(4), tx_id, amount: dec!(2) },
+            Event::Freeze { client },
+            Event::Unfreeze { client },
+            Event::Reinstate { client },
+            Event::FeeSweep { amount: dec!(0.1) },
+        ];
+        let mut jsonl = vec![];
+        write_jsonl(&mut jsonl, &events).unwrap();
+        let jsonl = String::from_utf8(jsonl).unwrap();
+        assert_eq!(events.len(), jsonl.lines().count());
+        for (event, line) in events.iter().zip(jsonl.lines()) {
+            assert_eq!(event, &serde_json::from_str::<Event>(line).unwrap());
+        }
+        assert!(jsonl.contains(r#"{"type":"dispute","client":1,"tx":2}"#));
+        assert!(serde_json::to_string(&Event::query(client).0).is_err());
+    }
+    #[test]
+    fn transaction_types_from_their_names() {
+        assert_eq!(Ok(TransactionType::Deposit), "deposit".parse());
+        assert_eq!(Ok(TransactionType::Chargeback), "ChargeBack".parse());
+        assert_eq!(Ok(TransactionType::FeeSweep), "FEE_SWEEP".parse());
+        for tx_type in TransactionType::ALL {
+            assert_eq!(Ok(tx_type), tx_type.to_string().parse());
+        }
+        assert_eq!(
+            Err("unknown transaction type `depositt`".to_string()),
+            "depositt".parse::<TransactionType>()
+        );
+        assert!("".parse::<TransactionType>().is_err());
+        assert_eq!(Ok(TransactionType::Deposit), " deposit\t".parse());
+        assert!("DEP OSIT".parse::<TransactionType>().is_err());
+    }
 }
@@ -0,0 +1,156 @@
+use accounting_engine::engine::{AccountingResult, Amount, ClientId};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// The first difference between the engine's results and an expected output.
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub client: ClientId,
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "client {}: expected {} {} but got {}",
+            self.client, self.field, self.expected, self.actual
+        )
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ExpectedRow {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    #[serde(default)]
+    pending: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// Reads account balances in the engine's own output format.
+pub fn read_expected<R: std::io::Read>(
+    mut reader: csv::Reader<R>,
+) -> Result<BTreeMap<ClientId, AccountingResult>, Box<dyn Error>> {
+    let mut expected = BTreeMap::new();
+    for row in reader.deserialize() {
+        let row: ExpectedRow = row?;
+        expected.insert(
+            row.client,
+            AccountingResult {
+                available: row.available,
+                held: row.held,
+                pending: row.pending,
+                total: row.total,
+                locked: row.locked,
+                purged: false,
+                external: row.total,
+                disputed_count: 0,
+                log: vec![],
+            },
+        );
+    }
+    Ok(expected)
+}
+
+/// Compares amounts by decimal value, so `1.50` matches `1.5`.
+pub fn verify(
+    actual: &BTreeMap<ClientId, AccountingResult>,
+    expected: &BTreeMap<ClientId, AccountingResult>,
+) -> Option<Mismatch> {
+    let clients: BTreeSet<_> = actual.keys().chain(expected.keys()).collect();
+    for client in clients {
+        let (actual, expected) = match (actual.get(client), expected.get(client)) {
+            (Some(actual), Some(expected)) => (actual, expected),
+            (actual, _) => {
+                let presence = |present: bool| if present { "present" } else { "missing" };
+                return Some(Mismatch {
+                    client: *client,
+                    field: "client",
+                    expected: presence(actual.is_none()).to_string(),
+                    actual: presence(actual.is_some()).to_string(),
+                });
+            }
+        };
+        let amounts = [
+            ("available", actual.available, expected.available),
+            ("held", actual.held, expected.held),
+            ("pending", actual.pending, expected.pending),
+            ("total", actual.total, expected.total),
+        ];
+        for (field, actual, expected) in amounts {
+            if actual != expected {
+                return Some(Mismatch {
+                    client: *client,
+                    field,
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+        if actual.locked != expected.locked {
+            return Some(Mismatch {
+                client: *client,
+                field: "locked",
+                expected: expected.locked.to_string(),
+                actual: actual.locked.to_string(),
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cli::InputOptions;
+    use crate::verify::{read_expected, verify, Mismatch};
+    use crate::{process_files, reader_builder};
+    use accounting_engine::engine::{AccountingEngine, ClientId};
+
+    async fn verify_files(input: &str, expected: &str) -> Option<Mismatch> {
+        let dir = tempfile::tempdir().unwrap();
+        let input_path = dir.path().join("input.csv");
+        std::fs::write(&input_path, input).unwrap();
+        let paths = vec![input_path.to_str().unwrap().to_string()];
+        let builder = AccountingEngine::builder();
+        let actual = process_files(&paths, None, builder, &InputOptions::default())
+            .await
+            .unwrap()
+            .unwrap()
+            .results;
+        let expected = read_expected(reader_builder().from_reader(expected.as_bytes())).unwrap();
+        verify(&actual, &expected)
+    }
+
+    const INPUT: &str = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,2,2,3\ndispute,2,2,\n";
+
+    #[tokio::test]
+    async fn matching_expected_output() {
+        let expected = "client,available,held,total,locked\n\
+                        1,1.5000,0,1.5,false\n\
+                        2, 0.0 , 3, 3.00 ,false\n";
+        assert_eq!(None, verify_files(INPUT, expected).await);
+    }
+    #[tokio::test]
+    async fn mismatching_expected_output() {
+        let expected = "client,available,held,total,locked\n\
+                        1,1.5,0,1.5,false\n\
+                        2,3,0,3,false\n";
+        assert_eq!(
+            Some(Mismatch {
+                client: ClientId(2),
+                field: "available",
+                expected: "3".to_string(),
+                actual: "0".to_string(),
+            }),
+            verify_files(INPUT, expected).await
+        );
+        let expected = "client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+        let mismatch = verify_files(INPUT, expected).await.unwrap();
+        assert_eq!((ClientId(2), "client"), (mismatch.client, mismatch.field));
+    }
+}
@@ -0,0 +1,91 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_accounting-engine"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+fn run_with_stdin(args: &[&str], input: &str) -> String {
+    let output = run(args, input);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn csv_is_read_from_stdin() {
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,3.5\n\
+        withdrawal,1,2,1\n\
+        deposit,2,3,2\n";
+    let expected = "client,available,held,total,locked\n\
+        1,2.5,0,2.5,false\n\
+        2,2,0,2,false\n";
+    assert_eq!(expected, run_with_stdin(&[], input));
+    assert_eq!(expected, run_with_stdin(&["-"], input));
+}
+#[test]
+fn rejected_rows_fail_the_run() {
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,3.5\n\
+        deposit,1,2,NaN\n";
+    let output = run(&[], input);
+    assert_eq!(Some(1), output.status.code());
+    assert_eq!(
+        "client,available,held,total,locked\n1,3.5,0,3.5,false\n",
+        String::from_utf8(output.stdout).unwrap()
+    );
+
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,3.5\n\
+        withdrawal,1,2,5\n";
+    assert_eq!(Some(1), run(&[], input).status.code());
+}
+#[test]
+fn rejected_rows_fail_a_matching_verify_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let expected = dir.path().join("expected.csv");
+    std::fs::write(
+        &expected,
+        "client,available,held,total,locked\n1,3.5,0,3.5,false\n",
+    )
+    .unwrap();
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,3.5\n\
+        deposit,1,2,NaN\n";
+    let output = run(&["verify", "-", expected.to_str().unwrap()], input);
+    assert_eq!(Some(1), output.status.code());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("matches"), "{}", stderr);
+    assert!(
+        stderr.contains("1 rows or events were rejected"),
+        "{}",
+        stderr
+    );
+}
+#[test]
+fn usage_errors_fail_the_run() {
+    for args in [
+        &["--bogus"][..],
+        &["verify", "transactions.csv"],
+        &["--scale"],
+        &["--uds", "engine.sock", "--dedup", "seen.txt"],
+        &["verify", "--uds", "engine.sock", "a.csv", "expected.csv"],
+    ] {
+        let output = run(args, "");
+        assert_eq!(Some(2), output.status.code(), "{:?}", args);
+        assert!(output.stdout.is_empty());
+        assert!(!output.stderr.is_empty());
+    }
+}